@@ -0,0 +1,190 @@
+use crate::parser::ParseError;
+use crate::span::{FileId, Span};
+use ariadne::{Color, Config, IndexType, Label, Report, ReportKind, Source};
+use chumsky::error::SimpleReason;
+use std::fmt;
+use std::io;
+
+/// Spec 24.1 Severity Levels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Severity {
+    /// Compilation halts immediately.
+    Fatal,
+    /// No valid artifact is produced, but scanning continues.
+    Error,
+    /// An auto-correction was applied; the output is still valid.
+    Warning,
+}
+
+/// A compiler message carrying a Spec Section 24 code (e.g. `E2001`).
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub code: &'static str,
+    pub message: String,
+    /// Primary location of the problem.
+    pub span: Span,
+    /// Secondary annotations (e.g. "first defined here").
+    pub labels: Vec<(Span, String)>,
+    pub help: Option<String>,
+}
+
+impl Diagnostic {
+    pub fn new(severity: Severity, code: &'static str, message: impl Into<String>, span: Span) -> Self {
+        Self { severity, code, message: message.into(), span, labels: Vec::new(), help: None }
+    }
+
+    pub fn fatal(code: &'static str, message: impl Into<String>, span: Span) -> Self {
+        Self::new(Severity::Fatal, code, message, span)
+    }
+
+    pub fn error(code: &'static str, message: impl Into<String>, span: Span) -> Self {
+        Self::new(Severity::Error, code, message, span)
+    }
+
+    pub fn warning(code: &'static str, message: impl Into<String>, span: Span) -> Self {
+        Self::new(Severity::Warning, code, message, span)
+    }
+
+    pub fn with_label(mut self, span: Span, message: impl Into<String>) -> Self {
+        self.labels.push((span, message.into()));
+        self
+    }
+
+    pub fn with_help(mut self, help: impl Into<String>) -> Self {
+        self.help = Some(help.into());
+        self
+    }
+
+    /// True for Fatal and Error severities (no valid artifact).
+    pub fn is_error(&self) -> bool {
+        self.severity != Severity::Warning
+    }
+
+    /// Builds the ariadne report. Spans are byte offsets into the `SourceMap`.
+    pub fn report(&self) -> Report<'static, Span> {
+        let (kind, color) = match self.severity {
+            Severity::Fatal => (ReportKind::Custom("Fatal", Color::Red), Color::Red),
+            Severity::Error => (ReportKind::Error, Color::Red),
+            Severity::Warning => (ReportKind::Warning, Color::Yellow),
+        };
+
+        let mut builder = Report::build(kind, self.span.file, self.span.start)
+            .with_config(Config::default().with_index_type(IndexType::Byte))
+            .with_code(self.code)
+            .with_message(&self.message)
+            .with_label(Label::new(self.span).with_message(&self.message).with_color(color));
+
+        for (span, msg) in &self.labels {
+            builder.add_label(Label::new(*span).with_message(msg).with_color(Color::Cyan));
+        }
+        if let Some(help) = &self.help {
+            builder.set_help(help);
+        }
+        builder.finish()
+    }
+
+    /// Renders the report to stderr.
+    pub fn eprint(&self, sources: &SourceMap) -> io::Result<()> {
+        self.report().eprint(sources)
+    }
+
+    /// Renders the report into `w` (without colors when `w` is not a terminal).
+    pub fn write<W: io::Write>(&self, sources: &SourceMap, w: W) -> io::Result<()> {
+        self.report().write(sources, w)
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.code, self.message)
+    }
+}
+
+// E1001 / E1002: Map chumsky errors onto the 1000-series codes.
+impl From<ParseError> for Diagnostic {
+    fn from(err: ParseError) -> Self {
+        let found = match err.found() {
+            Some(tok) => format!("`{}`", tok),
+            None => "end of input".to_string(),
+        };
+
+        match err.reason() {
+            SimpleReason::Unclosed { span, delimiter } => {
+                Diagnostic::error("E1002", format!("Unbalanced delimiter: `{}` is never closed", delimiter), err.span())
+                    .with_label(*span, "opened here")
+            }
+            SimpleReason::Custom(msg) => Diagnostic::error("E1001", msg.clone(), err.span()),
+            // Running out of input means a block was left open
+            SimpleReason::Unexpected if err.found().is_none() => {
+                Diagnostic::error("E1002", "Unbalanced delimiter: unexpected end of input", err.span())
+                    .with_help("A `{`, `[` or `(` was opened but never closed")
+            }
+            SimpleReason::Unexpected => {
+                let mut expected: Vec<String> = err.expected()
+                    .map(|t| match t {
+                        Some(tok) => format!("`{}`", tok),
+                        None => "end of input".to_string(),
+                    })
+                    .collect();
+                expected.sort();
+
+                let diag = Diagnostic::error("E1001", format!("Malformed token: unexpected {}", found), err.span());
+                if expected.is_empty() {
+                    diag
+                } else {
+                    diag.with_help(format!("Expected one of {}", expected.join(", ")))
+                }
+            }
+        }
+    }
+}
+
+/// The set of source files participating in a compilation, indexed by `FileId`.
+#[derive(Debug, Default)]
+pub struct SourceMap {
+    files: Vec<(String, Source<String>)>,
+}
+
+impl SourceMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a file and returns the id its spans should carry.
+    pub fn add(&mut self, name: impl Into<String>, text: String) -> FileId {
+        self.files.push((name.into(), Source::from(text)));
+        self.files.len() - 1
+    }
+
+    pub fn name(&self, id: FileId) -> &str {
+        &self.files[id].0
+    }
+
+    pub fn text(&self, id: FileId) -> &str {
+        self.files[id].1.text()
+    }
+
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+}
+
+impl ariadne::Cache<FileId> for &SourceMap {
+    type Storage = String;
+
+    fn fetch(&mut self, id: &FileId) -> Result<&Source<String>, Box<dyn fmt::Debug + '_>> {
+        match self.files.get(*id) {
+            Some((_, source)) => Ok(source),
+            None => Err(Box::new(format!("F9002: Unknown source file #{}", id))),
+        }
+    }
+
+    fn display<'a>(&self, id: &'a FileId) -> Option<Box<dyn fmt::Display + 'a>> {
+        self.files.get(*id).map(|(name, _)| Box::new(name.clone()) as Box<dyn fmt::Display>)
+    }
+}
@@ -1,32 +1,461 @@
-use crate::parser::{Score, TopLevel, Statement, Event as AstEvent, Value, Voice};
+use crate::parser::{Attribute, BarLine, Duration, Property, Score, TopLevel, Statement, Event as AstEvent, Value, Voice};
 use crate::Rational;
-use std::collections::HashMap;
+use crate::diagnostic::Diagnostic;
+use crate::lexer::Token;
+use logos::Logos;
+use crate::span::Span;
+use std::collections::{BTreeMap, HashMap};
 
 #[derive(Debug, Clone)]
 pub struct Timeline {
     pub title: String,
+    /// Opening tempo in BPM; `tempo_map` holds every later change.
     pub tempo: u32,
+    pub tempo_map: TempoMap,
     pub tracks: HashMap<String, Track>,
+    /// The measure grid, keyed by measure number (`0` is an anacrusis).
+    pub measures: BTreeMap<i64, MeasureInfo>,
+    /// Spec 7.3.1: fermata pauses, as extra playback ticks inserted at a
+    /// written tick. Notated positions are not shifted.
+    pub holds: BTreeMap<u64, u64>,
+    /// Spec 14.4-14.5: swing and humanization, applied by `playback::render`.
+    pub feel: Feel,
+    /// Non-fatal diagnostics (auto-corrections) raised during compilation.
+    pub warnings: Vec<Diagnostic>,
+}
+
+impl Timeline {
+    /// Wall-clock position of `tick`, following the tempo map.
+    pub fn tick_to_seconds(&self, tick: u64) -> f64 {
+        self.tempo_map.seconds_at(tick)
+    }
+
+    /// The tick sounding at `seconds` (rounded down).
+    pub fn seconds_to_tick(&self, seconds: f64) -> u64 {
+        self.tempo_map.tick_at(seconds)
+    }
+}
+
+/// Spec 14.4-14.5: micro-timing applied on playback only.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Feel {
+    /// Percentage of each swung pair given to its first note; `None` plays straight.
+    pub swing: Option<f64>,
+    /// Swung subdivision in ticks; `None` picks 8ths or 16ths from the tempo.
+    pub swing_grid: Option<u64>,
+    /// Jitter, as a fraction of each velocity and of a 16th note.
+    pub humanize: f64,
+    /// Seeds the jitter so renders are reproducible.
+    pub seed: u64,
+}
+
+/// Interpolation shape shared by tempo ramps and automation (Spec 14.3, 21.2).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Curve {
+    /// Holds the start value, jumping to the end value when the ramp ends.
+    #[default]
+    Step,
+    Linear,
+    /// Slow start, fast finish (accelerando, swells).
+    Exp,
+    /// Fast start, slow finish (ritardando).
+    Log,
+}
+
+impl Curve {
+    // Steepness of the exp/log shapes
+    const K: f64 = 4.0;
+
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "step" => Some(Curve::Step),
+            "linear" => Some(Curve::Linear),
+            "exp" => Some(Curve::Exp),
+            "log" => Some(Curve::Log),
+            _ => None,
+        }
+    }
+
+    /// Value at normalized position `t` (0.0 to 1.0) between `start` and `end`.
+    pub fn interpolate(self, start: f64, end: f64, t: f64) -> f64 {
+        let t = t.clamp(0.0, 1.0);
+        let shape = match self {
+            Curve::Step => if t < 1.0 { 0.0 } else { 1.0 },
+            Curve::Linear => t,
+            Curve::Exp => (Self::K * t).exp_m1() / Self::K.exp_m1(),
+            Curve::Log => (Self::K.exp_m1() * t).ln_1p() / Self::K,
+        };
+        start + (end - start) * shape
+    }
+}
+
+/// One tempo change. Before `end_tick` the tempo follows `curve` from
+/// `start_bpm` to `end_bpm`; afterwards it holds `end_bpm`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TempoSegment {
+    pub start_tick: u64,
+    pub end_tick: u64,
+    pub start_bpm: f64,
+    pub end_bpm: f64,
+    pub curve: Curve,
+}
+
+impl TempoSegment {
+    fn bpm_at(&self, tick: u64) -> f64 {
+        if tick >= self.end_tick { return self.end_bpm; }
+        let t = (tick - self.start_tick) as f64 / (self.end_tick - self.start_tick) as f64;
+        self.curve.interpolate(self.start_bpm, self.end_bpm, t)
+    }
+}
+
+/// Spec 14.3: The Time Map. Segments are sorted by `start_tick`; each lasts
+/// until the next one begins.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TempoMap {
+    pub ppq: u32,
+    pub segments: Vec<TempoSegment>,
+}
+
+impl TempoMap {
+    pub fn new(bpm: f64, ppq: u32) -> Self {
+        let mut map = Self { ppq, segments: Vec::new() };
+        map.set(0, bpm);
+        map
+    }
+
+    /// Static tempo change at `tick`.
+    pub fn set(&mut self, tick: u64, bpm: f64) {
+        self.ramp(tick, tick, bpm, bpm, Curve::Step);
+    }
+
+    /// Tempo transition across `start_tick..end_tick`.
+    pub fn ramp(&mut self, start_tick: u64, end_tick: u64, start_bpm: f64, end_bpm: f64, curve: Curve) {
+        // A later change at the same tick replaces the earlier one
+        self.segments.retain(|s| s.start_tick != start_tick);
+        let at = self.segments.partition_point(|s| s.start_tick < start_tick);
+        self.segments.insert(at, TempoSegment { start_tick, end_tick, start_bpm, end_bpm, curve });
+    }
+
+    fn segment_at(&self, tick: u64) -> Option<&TempoSegment> {
+        self.segments.iter().rev().find(|s| s.start_tick <= tick)
+    }
+
+    pub fn bpm_at(&self, tick: u64) -> f64 {
+        self.segment_at(tick).map_or(120.0, |s| s.bpm_at(tick))
+    }
+
+    /// Seconds elapsed from tick 0 to `tick`.
+    pub fn seconds_at(&self, tick: u64) -> f64 {
+        let mut seconds = 0.0;
+        for (i, seg) in self.segments.iter().enumerate() {
+            if seg.start_tick >= tick { break; }
+            let next = self.segments.get(i + 1).map_or(u64::MAX, |n| n.start_tick);
+            let until = tick.min(next);
+
+            // Ramp portion: integrate 60 / (bpm * ppq) with Simpson's rule
+            let ramp_end = until.min(seg.end_tick);
+            if ramp_end > seg.start_tick {
+                const STEPS: u64 = 256;
+                let span = (ramp_end - seg.start_tick) as f64;
+                let h = span / STEPS as f64;
+                let rate = |x: f64| 60.0 / (seg.bpm_at(seg.start_tick + x.round() as u64) * self.ppq as f64);
+                let mut sum = rate(0.0) + rate(span);
+                for k in 1..STEPS {
+                    sum += rate(k as f64 * h) * if k % 2 == 1 { 4.0 } else { 2.0 };
+                }
+                seconds += sum * h / 3.0;
+            }
+
+            // Held portion after the ramp
+            let held_from = seg.end_tick.max(seg.start_tick);
+            if until > held_from {
+                seconds += (until - held_from) as f64 * 60.0 / (seg.end_bpm * self.ppq as f64);
+            }
+        }
+        seconds
+    }
+
+    /// Inverse of `seconds_at`.
+    pub fn tick_at(&self, seconds: f64) -> u64 {
+        // Absorbs rounding error so exact tick boundaries map back exactly
+        let target = seconds + 1e-9;
+        let (mut lo, mut hi) = (0u64, 1u64);
+        while self.seconds_at(hi) <= target { hi *= 2; }
+        while lo < hi {
+            let mid = lo + (hi - lo).div_ceil(2);
+            if self.seconds_at(mid) <= target { lo = mid; } else { hi = mid - 1; }
+        }
+        lo
+    }
+}
+
+/// A `time` signature such as 3/4 (Spec 3.3).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TimeSignature {
+    pub numerator: u64,
+    pub denominator: u64,
+}
+
+impl TimeSignature {
+    pub fn new(numerator: u64, denominator: u64) -> Self {
+        Self { numerator, denominator }
+    }
+
+    /// Length of one full bar.
+    pub fn ticks(&self, ppq: u32) -> u64 {
+        Rational::new(self.numerator, self.denominator).to_ticks(ppq)
+    }
+}
+
+impl Default for TimeSignature {
+    fn default() -> Self {
+        Self::new(4, 4)
+    }
+}
+
+/// One cell of the measure grid.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct MeasureInfo {
+    pub start_tick: u64,
+    /// Equals the bar length, or the pickup duration for an anacrusis.
+    pub length_ticks: u64,
+    pub time_signature: TimeSignature,
+    /// Spec 11.1: the measure opens with `|:`.
+    pub repeat_start: bool,
+    pub barline: BarLine,
+    /// Spec 11.2: passes on which a volta bracket plays; empty for every pass.
+    pub volta: Vec<u32>,
+    /// Spec 11.3: navigation markers written in the measure.
+    pub markers: Vec<Marker>,
+}
+
+/// Spec 11.3: segno, coda and fine signs, and the jumps that target them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Marker {
+    Segno,
+    Coda,
+    ToCoda,
+    Fine,
+    DcAlFine,
+    DsAlFine,
+    DcAlCoda,
+    DsAlCoda,
+}
+
+impl Marker {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "segno" => Some(Marker::Segno),
+            "coda" => Some(Marker::Coda),
+            "to_coda" => Some(Marker::ToCoda),
+            "fine" => Some(Marker::Fine),
+            "dc_al_fine" => Some(Marker::DcAlFine),
+            "ds_al_fine" => Some(Marker::DsAlFine),
+            "dc_al_coda" => Some(Marker::DcAlCoda),
+            "ds_al_coda" => Some(Marker::DsAlCoda),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct Track {
     pub label: String,
     pub patch: String,
+    /// Spec 4.6: technique name -> MIDI note that selects it.
+    pub keyswitches: HashMap<String, u8>,
+    pub style: Style,
+    /// Spec 4.3: `channel=` (1-16). Auto-assigned when absent.
+    pub channel: Option<u8>,
+    /// Spec 14.1: controller changes, sorted by `start_tick`.
+    pub automation: Vec<Automation>,
+    /// Spec 14.1.3: a muted staff keeps its events but does not sound.
+    pub mute: bool,
+    /// Spec 14.1.3: while any staff is soloed, only soloed staves sound.
+    pub solo: bool,
     pub events: Vec<AtomicEvent>,
 }
 
+impl Track {
+    /// Adds a controller change; one at the same tick on the same controller is replaced.
+    fn automate(&mut self, automation: Automation) {
+        self.automation.retain(|a| a.controller != automation.controller || a.start_tick != automation.start_tick);
+        let at = self.automation.partition_point(|a| a.start_tick <= automation.start_tick);
+        self.automation.insert(at, automation);
+    }
+}
+
+/// One MIDI controller change on a staff, in controller units (0-127).
+/// Before `end_tick` the value follows `curve` from `from` to `to`;
+/// afterwards it holds `to`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Automation {
+    pub controller: u8,
+    pub start_tick: u64,
+    pub end_tick: u64,
+    pub from: f64,
+    pub to: f64,
+    pub curve: Curve,
+}
+
+impl Automation {
+    pub fn value_at(&self, tick: u64) -> f64 {
+        if tick >= self.end_tick { return self.to; }
+        let t = tick.saturating_sub(self.start_tick) as f64 / (self.end_tick - self.start_tick) as f64;
+        self.curve.interpolate(self.from, self.to, t)
+    }
+}
+
+/// Spec 4.2: the engine that interprets a staff's events.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum Style {
+    #[default]
+    Standard,
+    /// Open-string MIDI pitches, low to high, and the capo fret.
+    Tab { tuning: Vec<u8>, capo: u8 },
+    /// Percussion keys and the instruments they trigger.
+    Grid { map: HashMap<String, DrumKey> },
+}
+
+/// Spec 4.4: one entry of a `style=grid` map.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DrumKey {
+    /// Vertical staff offset (0 = bottom line).
+    pub position: i64,
+    pub note: u8,
+}
+
+/// Spec 23.3: the General MIDI kit as `(key, position, note)`.
+const GM_KIT: [(&str, i64, u8); 12] = [
+    ("k", 1, 36),
+    ("s", 5, 38),
+    ("ss", 5, 37),
+    ("t1", 7, 50),
+    ("t2", 6, 47),
+    ("t3", 3, 43),
+    ("h", 9, 42),
+    ("ho", 9, 46),
+    ("ph", -1, 44),
+    ("c", 10, 49),
+    ("r", 8, 51),
+    ("rb", 8, 53),
+];
+
+/// Spec 23.2: pre-defined tunings, low to high.
+fn standard_tuning(name: &str) -> Option<Vec<u8>> {
+    Some(match name {
+        "guitar_std" => vec![40, 45, 50, 55, 59, 64],
+        "guitar_drop_d" => vec![38, 45, 50, 55, 59, 64],
+        "bass_std" => vec![28, 33, 38, 43],
+        "bass_5" => vec![23, 28, 33, 38, 43],
+        "uke_std" => vec![67, 60, 64, 69],
+        "violin_std" => vec![55, 62, 69, 76],
+        "cello_std" => vec![36, 43, 50, 57],
+        _ => return None,
+    })
+}
+
+/// Spec 5.4: `:grace`/`:grace.slash` or `:grace.noSlash`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Grace {
+    Acciaccatura,
+    Appoggiatura,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tie {
+    Start,
+    Continue,
+    Stop,
+}
+
+/// Spec 8.1: a tab coordinate. String 1 is the highest string.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TabPosition {
+    pub fret: u8,
+    pub string: u8,
+}
+
 #[derive(Debug, Clone)]
 pub struct AtomicEvent {
     pub tick: u64,          
+    /// Notated length, as engraved.
     pub duration_ticks: u64,
+    /// Spec 7.3: playback length after the articulation gate.
+    pub sounding_ticks: u64,
     pub kind: EventKind,
+    /// Spec 10.2 voice layer (`v1` = 1). Plain staff streams are voice 1.
+    pub voice: u8,
+    /// Fret and string of notes on tablature staves.
+    pub tab: Option<TabPosition>,
+    /// Spec 6.6: role in a tie chain. Only the first note of a chain sounds,
+    /// for the length of the whole chain.
+    pub tie: Option<Tie>,
+    /// Spec 5.4: set on grace notes, which take no metric time.
+    pub grace: Option<Grace>,
+    /// Playback displacement of the attack from `tick`.
+    pub offset_ticks: i64,
+    /// Spec 21.1-21.2: `.cc()` messages sent with the attack. Their ticks
+    /// count from the event's onset.
+    pub controls: Vec<Automation>,
+    /// Source location of the AST event that produced this atom.
+    pub span: Span,
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum EventKind {
     Note { pitch: u8, velocity: u8 }, 
     Rest,
+    /// Silent trigger selecting a sample-library articulation (Spec 4.6).
+    KeySwitch { pitch: u8 },
+}
+
+/// Spec 7.4: built-in sticky techniques.
+const TECHNIQUES: [&str; 6] = ["pizz", "arco", "mute", "open", "harm", "harm_art"];
+
+/// Spec 27.2: gate time, in percent, for notes without a length articulation.
+const DEFAULT_GATE: u64 = 90;
+
+/// Spec 7.3.1: a fermata holds its note for twice the written length.
+const DEFAULT_FERMATA: f64 = 2.0;
+
+/// Spec 7.3: sounding length of a note with the given notated length.
+/// When several gates are marked the shortest wins.
+fn gate_ticks(attributes: &[Attribute], ticks: u64) -> u64 {
+    let percent = attributes.iter()
+        .filter_map(|attr| match attr.name.as_str() {
+            "stacc" => Some(50),
+            "stacciss" => Some(25),
+            "ten" => Some(100),
+            _ => None,
+        })
+        .min()
+        .unwrap_or(DEFAULT_GATE);
+    (ticks * percent / 100).max(1)
+}
+
+/// Velocity before any dynamic has been marked.
+const DEFAULT_VELOCITY: u8 = 100;
+
+/// Spec 27.2: MIDI velocity for each sticky dynamic token (Spec 7.2). A
+/// velocity only carries the attack, so `fp` holds its forte.
+fn dynamic_velocity(name: &str) -> Option<u8> {
+    Some(match name {
+        "pppp" => 16,
+        "ppp" => 28,
+        "pp" => 40,
+        "p" => 52,
+        "mp" => 66,
+        "mf" => 80,
+        "f" => 96,
+        "ff" => 108,
+        "fff" => 118,
+        "ffff" => 127,
+        "sfz" => 112,
+        "rfz" => 104,
+        "fp" => 96,
+        _ => return None,
+    })
 }
 
 struct Cursor {
@@ -35,9 +464,80 @@ struct Cursor {
     last_octave: u8,
     // Time Scalar for Tuplets. Standard = 1/1. Triplet = 2/3.
     time_scalar: Rational,
+    // Semitone offset applied to pitches inside transposed macros (Spec 15.3)
+    transpose: i64,
+    // Voice layer stamped on emitted events
+    voice: u8,
+    // Sticky dynamic (Spec 7.2): CurrentAmplitude as a MIDI velocity
+    velocity: u8,
+    // Sticky technique (Spec 7.4)
+    technique: Option<String>,
+    // Ties left open by the previous event
+    ties: Vec<OpenTie>,
+    // Grace notes waiting for their principal, and the last principal's events
+    graces: Vec<PendingGrace>,
+    previous: Vec<usize>,
+    // Whether a `* n` multiplier was used since the measure began
+    multiplied: bool,
+    // Exact metric time consumed, in whole notes; ticks round each event down
+    elapsed: Rational,
+    // Fermatas since the measure began: (end tick, notated length)
+    fermatas: Vec<(u64, u64)>,
     ppq: u32, 
 }
 
+struct OpenTie {
+    pitch: u8,
+    /// Index in `Track::events` of the first and latest note of the chain.
+    head: usize,
+    last: usize,
+    span: Span,
+}
+
+struct PendingGrace {
+    /// Indices in `Track::events` (several for a grace chord).
+    events: Vec<usize>,
+    /// `:grace.slash` is played before the beat.
+    before_beat: bool,
+}
+
+/// Spec 5.4: the written grace form and whether it plays before the beat.
+fn grace_kind(duration: Option<&Duration>) -> Option<(Grace, bool)> {
+    match duration.map(|d| d.text.as_str()) {
+        Some(":grace") => Some((Grace::Acciaccatura, false)),
+        Some(":grace.slash") => Some((Grace::Acciaccatura, true)),
+        Some(":grace.noSlash") => Some((Grace::Appoggiatura, false)),
+        _ => None,
+    }
+}
+
+/// Spec 5.1: a note value as a fraction of a whole note. `4` is a quarter,
+/// `0.5` a breve and `3/8` is taken literally. `None` for anything else.
+fn parse_note_value(value: &str) -> Option<Rational> {
+    if let Some((n, d)) = value.split_once('/') {
+        let (n, d) = (n.parse::<u64>().ok()?, d.parse::<u64>().ok()?);
+        (n > 0 && d > 0).then(|| Rational::new(n, d))
+    } else if let Some((int, frac)) = value.split_once('.') {
+        // Reciprocal of a decimal: 1 / 0.5 = 10 / 5
+        let scale = 10u64.checked_pow(frac.len().try_into().ok()?)?;
+        let digits = format!("{}{}", int, frac).parse::<u64>().ok()?;
+        (digits > 0).then(|| Rational::new(scale, digits))
+    } else {
+        let d = value.parse::<u64>().ok()?;
+        (d > 0).then(|| Rational::new(1, d))
+    }
+}
+
+/// A duration literal measured by `Cursor::length`.
+struct Length {
+    /// The dotted note value, which becomes the sticky duration.
+    value: Rational,
+    count: u64,
+    /// Whole notes after the multiplier and any tuplet scaling.
+    whole: Rational,
+    ticks: u64,
+}
+
 impl Cursor {
     fn new(ppq: u32) -> Self {
         Self {
@@ -45,36 +545,107 @@ impl Cursor {
             last_duration: Rational::new(1, 4), 
             last_octave: 4,  
             time_scalar: Rational::new(1, 1),
+            transpose: 0,
+            voice: 1,
+            velocity: DEFAULT_VELOCITY,
+            technique: None,
+            ties: Vec::new(),
+            graces: Vec::new(),
+            previous: Vec::new(),
+            multiplied: false,
+            elapsed: Rational::new(0, 1),
+            fermatas: Vec::new(),
             ppq,
         }
     }
 
-    fn parse_duration(&mut self, d_str: Option<&String>) -> u64 {
-        let base_rat = if let Some(s) = d_str {
-            let raw = &s[1..];
-            let dots = raw.chars().filter(|&c| c == '.').count();
-            let base_str: String = raw.chars().take_while(|&c| c != '.').collect();
-            let denominator: u64 = base_str.parse().unwrap_or(4);
-            
-            let mut rat = Rational::new(1, denominator);
-            if dots == 1 { rat = Rational::new(3, denominator * 2); }
-            else if dots == 2 { rat = Rational::new(7, denominator * 4); }
-            
-            self.last_duration = rat;
-            rat
-        } else {
-            self.last_duration
+    /// Spec 5.1: measures a duration literal such as `:8.` or `:4*3` with
+    /// checked arithmetic. `None` if it is not a positive note value, or if
+    /// it overflows or falls outside the one tick to `u32::MAX` ticks that
+    /// MIDI export can write.
+    fn length(&self, d: &str) -> Option<Length> {
+        let raw = d.strip_prefix(':')?;
+        // Spec 5.1.2: the multiplier applies to this event only
+        let (raw, count) = match raw.split_once('*') {
+            Some((raw, n)) => (raw, n.parse::<u64>().ok().filter(|&n| n > 0)?),
+            None => (raw, 1),
         };
+        let base = raw.trim_end_matches('.');
+        // Each dot adds half of the previous value: n dots = (2^(n+1) - 1) / 2^n
+        let dots = u32::try_from(raw.len() - base.len()).ok()?;
+        let dotted = Rational::new(2u64.checked_pow(dots.checked_add(1)?)? - 1, 2u64.checked_pow(dots)?);
+        let value = parse_note_value(base)?.checked_mul(dotted)?;
+        let whole = value.checked_mul(Rational::new(count, 1))?.checked_mul(self.time_scalar)?;
+        let ticks = whole.checked_ticks(self.ppq).filter(|&t| (1..=u32::MAX as u64).contains(&t))?;
+        self.elapsed.checked_add(whole)?;
+        Some(Length { value, count, whole, ticks })
+    }
+
+    fn parse_duration(&mut self, duration: Option<&Duration>) -> u64 {
+        // Grace notes take no metric time and leave the sticky duration alone
+        if grace_kind(duration).is_some() { return 0; }
+
+        // An invalid literal (reported by the caller) keeps the sticky duration
+        if let Some(length) = duration.and_then(|d| self.length(&d.text)) {
+            self.last_duration = length.value;
+            self.multiplied |= length.count > 1;
+            self.elapsed = self.elapsed.checked_add(length.whole).unwrap_or(self.elapsed);
+            return length.ticks;
+        }
 
         // Apply Time Scalar (for Tuplets)
         // Duration = Base * Scalar
         // e.g. 1/8 * (2/3) = 1/12 (Triplet eighth)
-        let final_rat = Rational::new(
-            base_rat.num * self.time_scalar.num,
-            base_rat.den * self.time_scalar.den
-        );
+        let whole = self.last_duration.checked_mul(self.time_scalar).unwrap_or(self.last_duration);
+        self.elapsed = self.elapsed.checked_add(whole).unwrap_or(self.elapsed);
+        whole.to_ticks(self.ppq)
+    }
+
+    /// Applies the dynamics in `attributes` and returns this event's velocity.
+    /// `acc` and `marc` boost the result by 15% and 25%.
+    fn velocity(&mut self, attributes: &[Attribute], diagnostics: &mut Vec<Diagnostic>) -> u8 {
+        let mut explicit = None;
+        let mut boost = 0;
+        for attr in attributes {
+            match attr.name.as_str() {
+                "acc" => boost = boost.max(15),
+                "marc" => boost = boost.max(25),
+                "vel" => match attr.args.first().and_then(number) {
+                    Some(v) => {
+                        if !(0.0..=127.0).contains(&v) {
+                            diagnostics.push(Diagnostic::warning("W4003", format!("Value out of range: velocity {} clamped to 0-127", v), attr.span));
+                        }
+                        explicit = Some(v.clamp(0.0, 127.0).round() as u8);
+                    }
+                    None => diagnostics.push(Diagnostic::error("E4002", "Invalid type cast: `.vel` expects a number", attr.span)),
+                },
+                name => if let Some(v) = dynamic_velocity(name) { self.velocity = v; },
+            }
+        }
+        let velocity = explicit.unwrap_or(self.velocity) as u32;
+        (velocity * (100 + boost) / 100).min(127) as u8
+    }
 
-        final_rat.to_ticks(self.ppq)
+    /// Spec 7.3.1: notes a `.fermata` on an event starting at the cursor.
+    fn fermata(&mut self, attributes: &[Attribute], ticks: u64) {
+        if ticks > 0 && attributes.iter().any(|a| a.name == "fermata") {
+            self.fermatas.push((self.current_tick + ticks, ticks));
+        }
+    }
+
+    /// Applies the techniques in `attributes`. Returns the keyswitch to
+    /// send when the active technique changes to one the track maps.
+    fn technique(&mut self, attributes: &[Attribute], keyswitches: &HashMap<String, u8>) -> Option<u8> {
+        let mut changed = None;
+        for attr in attributes {
+            let name = attr.name.as_str();
+            if !TECHNIQUES.contains(&name) && !keyswitches.contains_key(name) { continue; }
+            if self.technique.as_deref() != Some(name) {
+                self.technique = Some(name.to_string());
+                changed = Some(name);
+            }
+        }
+        changed.and_then(|name| keyswitches.get(name).copied())
     }
 
     fn parse_pitch(&mut self, p_str: &str) -> u8 {
@@ -90,146 +661,1234 @@ impl Cursor {
         while i < chars.len() {
             match chars[i] {
                 '#' => base += 1, 'b' => base -= 1, 
-                c if c.is_digit(10) => {
-                    if !has_explicit_octave {
-                        octave = c.to_digit(10).unwrap() as u8;
-                        has_explicit_octave = true;
-                    }
+                c if c.is_ascii_digit() && !has_explicit_octave => {
+                    octave = c.to_digit(10).unwrap() as u8;
+                    has_explicit_octave = true;
                 }
                 _ => {}
             }
             i += 1;
         }
         if has_explicit_octave { self.last_octave = octave; }
-        (octave + 1) * 12 + base
+        let midi = ((octave + 1) * 12 + base) as i64 + self.transpose;
+        midi.clamp(0, 127) as u8
+    }
+}
+
+/// Spec 16.2: All `measure N` blocks sharing an index, merged additively.
+#[derive(Default)]
+struct OpenMeasure<'a> {
+    assignments: Vec<&'a Statement>,
+    meta: HashMap<&'a str, (&'a Value, Span)>,
+}
+
+/// Parses a `time` value ("3/4").
+fn parse_time_signature(value: &Value) -> Option<TimeSignature> {
+    let Value::Str(s) = value else { return None };
+    let (n, d) = s.split_once('/')?;
+    let (n, d) = (n.trim().parse().ok()?, d.trim().parse().ok()?);
+    (n > 0 && d > 0).then_some(TimeSignature::new(n, d))
+}
+
+/// Where a mixer value applies. A number takes effect at the start of
+/// `ticks`; `[from, to]` ramps across them with `curve`.
+struct MixerScope {
+    ticks: std::ops::Range<u64>,
+    curve: Option<Curve>,
+    /// `def` and the top-level `meta`, which hold for the whole staff.
+    global: bool,
+}
+
+impl MixerScope {
+    fn global() -> Self {
+        Self { ticks: 0..0, curve: Some(Curve::Step), global: true }
+    }
+}
+
+/// Spec 14.1: `staff.param` meta keys.
+fn mixer_meta(timeline: &mut Timeline, key: &str, value: &Value, scope: &MixerScope, span: Span, diagnostics: &mut Vec<Diagnostic>) {
+    let (staff, param) = key.split_once('.').unwrap_or((key, ""));
+    let Some(track) = timeline.tracks.get_mut(staff) else {
+        diagnostics.push(
+            Diagnostic::error("E2001", format!("Undefined staff `{}`", staff), span)
+                .with_help(format!("Register it first, e.g. `def {} \"Label\"`", staff))
+        );
+        return;
+    };
+    if !apply_mixer(track, param, value, scope, span, diagnostics) {
+        diagnostics.push(
+            Diagnostic::error("E4002", format!("Invalid type cast: `{}` is not a mixer parameter", param), span)
+                .with_help("Use `vol`, `pan`, `reverb`, `chorus`, `mute` or `solo`")
+        );
+    }
+}
+
+/// Applies one mixer parameter to `track` across `scope`. `mute` and `solo`
+/// hold for the whole staff, so only a global scope may set them.
+/// Returns false when `param` is not a mixer parameter.
+fn apply_mixer(track: &mut Track, param: &str, value: &Value, scope: &MixerScope, span: Span, diagnostics: &mut Vec<Diagnostic>) -> bool {
+    // CC number and the parameter's range, scaled onto 0-127
+    let (controller, range) = match param {
+        "vol" => (7, 0.0..=1.0),
+        "pan" => (10, -1.0..=1.0),
+        "reverb" => (91, 0.0..=1.0),
+        "chorus" => (93, 0.0..=1.0),
+        "mute" | "solo" => {
+            let flag = match value {
+                Value::Id(b) if b == "true" || b == "false" => b == "true",
+                _ => {
+                    diagnostics.push(Diagnostic::error("E4002", format!("Invalid type cast: `{}` expects `true` or `false`", param), span));
+                    return true;
+                }
+            };
+            if !scope.global {
+                diagnostics.push(
+                    Diagnostic::warning("W4003", format!("Value out of range: `{}` holds for the whole staff and is ignored in a measure `meta`", param), span)
+                        .with_help("Set it on the `def` or in the top-level `meta`")
+                );
+            } else if param == "mute" {
+                track.mute = flag;
+            } else {
+                track.solo = flag;
+            }
+            return true;
+        }
+        _ => return false,
+    };
+    let (lo, hi) = (*range.start(), *range.end());
+    let scale = |v: f64| (v - lo) / (hi - lo) * 127.0;
+
+    let (from, to, curve) = match value {
+        Value::Array(pair) if pair.len() == 2 => {
+            let Some(curve) = scope.curve else {
+                diagnostics.push(
+                    Diagnostic::error("E4002", "Invalid type cast: unknown automation curve", span)
+                        .with_help("Use \"step\", \"linear\", \"exp\" or \"log\"")
+                );
+                return true;
+            };
+            match (ranged(&pair[0], param, range.clone(), span, diagnostics), ranged(&pair[1], param, range, span, diagnostics)) {
+                (Some(from), Some(to)) => (from, to, curve),
+                _ => return true,
+            }
+        }
+        Value::Array(_) => {
+            diagnostics.push(Diagnostic::error("E4002", format!("Invalid type cast: `{}` ramps need two numbers", param), span));
+            return true;
+        }
+        v => match ranged(v, param, range, span, diagnostics) {
+            Some(level) => (level, level, Curve::Step),
+            None => return true,
+        },
+    };
+    let end_tick = if curve == Curve::Step && from == to { scope.ticks.start } else { scope.ticks.end };
+    track.automate(Automation { controller, start_tick: scope.ticks.start, end_tick, from: scale(from), to: scale(to), curve });
+    true
+}
+
+/// A numeric meta value, clamped into `range` with W4003.
+fn ranged(value: &Value, name: &str, range: std::ops::RangeInclusive<f64>, span: Span, diagnostics: &mut Vec<Diagnostic>) -> Option<f64> {
+    let Some(n) = number(value) else {
+        diagnostics.push(Diagnostic::error("E4002", format!("Invalid type cast: `{}` expects a number", name), span));
+        return None;
+    };
+    let (lo, hi) = (*range.start(), *range.end());
+    if !range.contains(&n) {
+        diagnostics.push(Diagnostic::warning("W4003", format!("Value out of range: {} {} clamped to {}-{}", name, n, lo, hi), span));
+    }
+    Some(n.clamp(lo, hi))
+}
+
+/// A ramp's `curve`, `None` if it names no curve. Without one, tempo ramps
+/// step (Spec 14.3) and automation ramps are linear (Spec 21.2).
+fn ramp_curve(value: Option<&Value>, tempo: bool) -> Option<Curve> {
+    match value {
+        Some(Value::Str(name)) => Curve::parse(name),
+        Some(_) => None,
+        None if tempo => Some(Curve::Step),
+        None => Some(Curve::Linear),
+    }
+}
+
+/// A duration in meta such as `swing_grid: ":16"` or `pickup: ":8"`; the
+/// colon may be left out.
+fn parse_duration_meta(key: &str, value: &Value, ppq: u32, span: Span, diagnostics: &mut Vec<Diagnostic>) -> Option<u64> {
+    let length = match value {
+        Value::Str(d) => Cursor::new(ppq).length(&format!(":{}", d.trim_start_matches(':'))),
+        _ => None,
+    };
+    match length {
+        Some(length) => Some(length.ticks),
+        None => {
+            diagnostics.push(Diagnostic::error("E4002", format!("Invalid type cast: `{}` expects a duration such as \":8\"", key), span));
+            None
+        }
+    }
+}
+
+/// Spec 14.3: a static `tempo`, which must be a positive, finite BPM.
+fn parse_bpm(value: &Value, span: Span, diagnostics: &mut Vec<Diagnostic>) -> Option<f64> {
+    let bpm = number(value).filter(|&bpm| bpm_ok(bpm));
+    if bpm.is_none() {
+        diagnostics.push(Diagnostic::error("E4002", "Invalid type cast: tempo expects a positive number of beats per minute", span));
+    }
+    bpm
+}
+
+fn bpm_ok(bpm: f64) -> bool {
+    bpm.is_finite() && bpm > 0.0
+}
+
+/// Spec 7.3.1: `fermata: 1.5` scales the held note; below 1 would shorten it.
+fn parse_fermata(value: &Value, span: Span, diagnostics: &mut Vec<Diagnostic>) -> Option<f64> {
+    match number(value) {
+        Some(factor) if factor >= 1.0 => Some(factor),
+        _ => {
+            diagnostics.push(Diagnostic::error("E4002", "Invalid type cast: `fermata` expects a hold factor of at least 1", span));
+            None
+        }
+    }
+}
+
+/// Spec 11.2: `volta: "1."`, `volta: "1,3."` or `volta: 2`.
+fn parse_volta(value: &Value) -> Option<Vec<u32>> {
+    let passes = match value {
+        Value::Num(n) => vec![u32::try_from(*n).ok()?],
+        Value::Str(s) => s.trim().trim_end_matches('.').split(',')
+            .map(|pass| pass.trim().parse().ok())
+            .collect::<Option<Vec<u32>>>()?,
+        _ => return None,
+    };
+    (!passes.contains(&0)).then_some(passes)
+}
+
+/// Bar lines and markers of a measure, merged across its staves.
+/// Spec 11.1: bar lines are system-global, so written ones must agree (E3004).
+fn measure_structure(index: i64, assignments: &[&Statement], diagnostics: &mut Vec<Diagnostic>) -> MeasureInfo {
+    let mut structure = MeasureInfo::default();
+    let mut written: Option<(&str, BarLine, Span)> = None;
+    // The first staff to write any bar line, and whether it opened a repeat
+    let mut opened: Option<(&str, bool, Span)> = None;
+    for stmt in assignments {
+        let Statement::Assignment { staff_id, voices, repeat_start, barline, span } = stmt else { continue };
+        for voice in voices {
+            collect_markers(voice, &mut structure.markers);
+        }
+        if *repeat_start || barline.is_some() {
+            match opened {
+                Some((first, prev, prev_span)) if prev != *repeat_start => {
+                    let (opens, plain) = if *repeat_start { (staff_id.as_str(), first) } else { (first, staff_id.as_str()) };
+                    diagnostics.push(
+                        Diagnostic::error(
+                            "E3004",
+                            format!("Structure mismatch in measure {}: `{}` opens a repeat with `|:` but `{}` does not", index, opens, plain),
+                            *span,
+                        )
+                        .with_label(prev_span, "bar line first written here")
+                        .with_help("Bar lines and repeats apply to every staff; write the same one on each")
+                    );
+                }
+                Some(_) => {}
+                None => {
+                    opened = Some((staff_id, *repeat_start, *span));
+                    structure.repeat_start = *repeat_start;
+                }
+            }
+        }
+        let Some(barline) = *barline else { continue };
+        match written {
+            Some((first, prev, prev_span)) if prev != barline => diagnostics.push(
+                Diagnostic::error(
+                    "E3004",
+                    format!("Structure mismatch in measure {}: `{}` ends with {} but `{}` ends with {}", index, staff_id, describe_barline(barline), first, describe_barline(prev)),
+                    *span,
+                )
+                .with_label(prev_span, "bar line first written here")
+                .with_help("Bar lines and repeats apply to every staff; write the same one on each")
+            ),
+            Some(_) => {}
+            None => {
+                written = Some((staff_id, barline, *span));
+                structure.barline = barline;
+            }
+        }
+    }
+    structure
+}
+
+fn collect_markers(voice: &Voice, markers: &mut Vec<Marker>) {
+    for event in &voice.events {
+        let attributes = match event {
+            AstEvent::Note { attributes, .. } | AstEvent::Chord { attributes, .. }
+            | AstEvent::Tab { attributes, .. } | AstEvent::Percussion { attributes, .. } => attributes,
+            AstEvent::Tuplet { content, .. } | AstEvent::Transposed { content, .. } => {
+                collect_markers(content, markers);
+                continue;
+            }
+            AstEvent::VoiceGroup { voices, .. } => {
+                voices.iter().for_each(|v| collect_markers(v, markers));
+                continue;
+            }
+            AstEvent::Rest { .. } | AstEvent::MacroCall { .. } => continue,
+        };
+        for marker in attributes.iter().filter_map(|a| Marker::parse(&a.name)) {
+            if !markers.contains(&marker) { markers.push(marker); }
+        }
+    }
+}
+
+fn describe_barline(barline: BarLine) -> &'static str {
+    match barline {
+        BarLine::Single => "`|`",
+        BarLine::Double => "`||`",
+        BarLine::Final => "`|]`",
+        BarLine::RepeatEnd => "`:|`",
+        BarLine::RepeatDouble => "`:|:`",
+    }
+}
+
+/// Numeric meta values (`120`, `92.5`).
+fn number(value: &Value) -> Option<f64> {
+    match value {
+        Value::Num(n) => Some(*n as f64),
+        Value::Float(f) => Some(*f),
+        _ => None,
     }
 }
 
-pub fn compile(score: Score) -> Result<Timeline, String> {
+/// Renders a tick count as a fraction of a whole note ("5/4") for messages.
+fn describe_ticks(ticks: u64, ppq: u32) -> String {
+    let r = Rational::new(ticks, 4 * ppq as u64);
+    format!("{}/{}", r.num, r.den)
+}
+
+/// Linearizes a Score. Fails with every diagnostic if any of them is an error.
+pub fn compile(score: Score) -> Result<Timeline, Vec<Diagnostic>> {
     let mut timeline = Timeline {
         title: "Untitled".into(),
         tempo: 120,
+        tempo_map: TempoMap::new(120.0, 1920),
         tracks: HashMap::new(),
+        measures: BTreeMap::new(),
+        holds: BTreeMap::new(),
+        feel: Feel::default(),
+        warnings: Vec::new(),
     };
+    let mut diagnostics = Vec::new();
+    // Global Symbol Table (Spec 16.3): defs from every imported file land here
+    let mut def_spans: HashMap<String, Span> = HashMap::new();
+    let mut time_sig = TimeSignature::default();
+    let mut fermata = DEFAULT_FERMATA;
+    // `vln.vol: 0.8` in the top-level meta, applied once every staff is defined
+    let mut staff_meta = Vec::new();
 
     // 1. Context Building
     for item in &score.items {
         match item {
-            TopLevel::Meta(kvs) => {
-                for (k, v) in kvs {
+            TopLevel::Meta(kvs, _) => {
+                for Property { key: k, value: v, span } in kvs {
                     if k == "title" { if let Value::Str(s) = v { timeline.title = s.clone(); } }
-                    else if k == "tempo" { if let Value::Num(n) = v { timeline.tempo = *n as u32; } }
+                    else if k == "tempo" {
+                        if let Some(bpm) = parse_bpm(v, *span, &mut diagnostics) {
+                            timeline.tempo = bpm as u32;
+                            timeline.tempo_map.set(0, bpm);
+                        }
+                    }
+                    else if k == "time" { time_sig = parse_time_signature(v).unwrap_or(time_sig); }
+                    else if k == "fermata" { fermata = parse_fermata(v, *span, &mut diagnostics).unwrap_or(fermata); }
+                    else if k == "swing" { timeline.feel.swing = ranged(v, "swing", 0.0..=100.0, *span, &mut diagnostics); }
+                    else if k == "swing_grid" { timeline.feel.swing_grid = parse_duration_meta(k, v, timeline.tempo_map.ppq, *span, &mut diagnostics); }
+                    else if k == "humanize" { timeline.feel.humanize = ranged(v, "humanize", 0.0..=1.0, *span, &mut diagnostics).unwrap_or(0.0); }
+                    else if k.contains('.') { staff_meta.push((k, v, *span)); }
+                    else if k == "seed" {
+                        match v {
+                            Value::Num(n) if *n >= 0 => timeline.feel.seed = *n as u64,
+                            _ => diagnostics.push(Diagnostic::error("E4002", "Invalid type cast: `seed` expects a non-negative integer", *span)),
+                        }
+                    }
                 }
             },
-            TopLevel::Def { id, label, attributes } => {
+            TopLevel::Def { id, label, attributes, span } => {
+                if let Some(first) = def_spans.get(id) {
+                    diagnostics.push(
+                        Diagnostic::error("E2002", format!("Duplicate definition of staff `{}`", id), *span)
+                            .with_label(*first, "first defined here")
+                    );
+                    continue;
+                }
+                def_spans.insert(id.clone(), *span);
+
                 let mut patch = "Grand Piano".to_string();
-                for (attr, val) in attributes {
+                let mut keyswitches = HashMap::new();
+                for Property { key: attr, value: val, span } in attributes {
                     if attr == "patch" { if let Value::Str(s) = val { patch = s.clone(); } }
+                    if attr == "keyswitch" { keyswitches = parse_keyswitches(val, *span, &mut diagnostics); }
                 }
-                timeline.tracks.insert(id.clone(), Track {
+                let style = parse_style(id, attributes, *span, &mut diagnostics);
+                let channel = parse_channel(attributes, &mut diagnostics);
+                let mut track = Track {
                     label: label.clone(),
                     patch,
+                    keyswitches,
+                    style,
+                    channel,
+                    automation: Vec::new(),
+                    mute: false,
+                    solo: false,
                     events: Vec::new(),
-                });
+                };
+                for Property { key: attr, value: val, span } in attributes {
+                    apply_mixer(&mut track, attr, val, &MixerScope::global(), *span, &mut diagnostics);
+                }
+                timeline.tracks.insert(id.clone(), track);
             },
             _ => {}
         }
     }
 
-    // 2. Linearization
-    let ppq = 1920;
-    // Map of StaffID -> [Cursor for Voice 1, Cursor for Voice 2...]
-    let mut cursors: HashMap<String, Vec<Cursor>> = HashMap::new();
-
-    for id in timeline.tracks.keys() {
-        // Start with 4 voices per track as default, can expand dynamically
-        cursors.insert(id.clone(), vec![
-            Cursor::new(ppq), Cursor::new(ppq), Cursor::new(ppq), Cursor::new(ppq)
-        ]);
+    for (key, value, span) in staff_meta {
+        mixer_meta(&mut timeline, key, value, &MixerScope::global(), span, &mut diagnostics);
     }
 
+    // 2. Cursor Setup
+    let ppq = 1920;
+    // Map of StaffID -> the staff's sticky state (shared with `v1` of voice groups)
+    let mut cursors: HashMap<String, Cursor> = timeline.tracks.keys()
+        .map(|id| (id.clone(), Cursor::new(ppq)))
+        .collect();
+
+    // 3. Open Measure Merge: index every block, unnumbered ones follow the previous
+    let mut measures: BTreeMap<i64, OpenMeasure> = BTreeMap::new();
+    let mut next_index = 1;
     for item in &score.items {
-        if let TopLevel::Measure { content, .. } = item {
-            for stmt in content {
-                match stmt {
-                    Statement::Assignment { staff_id, voices } => {
-                        if let Some(track) = timeline.tracks.get_mut(staff_id) {
-                            let track_cursors = cursors.get_mut(staff_id).unwrap();
-                            
-                            // Process each voice in parallel
-                            for (v_idx, voice) in voices.iter().enumerate() {
-                                if v_idx >= track_cursors.len() {
-                                    track_cursors.push(Cursor::new(ppq));
-                                }
-                                let cursor = &mut track_cursors[v_idx];
-                                process_voice(voice, cursor, track);
-                            }
+        let TopLevel::Measure { id, content, .. } = item else { continue };
+        let index = id.unwrap_or(next_index);
+        next_index = index + 1;
+
+        let measure = measures.entry(index).or_default();
+        for stmt in content {
+            match stmt {
+                Statement::Assignment { .. } => measure.assignments.push(stmt),
+                // Resolved by the pre-processor
+                Statement::If { .. } => {}
+                Statement::LocalMeta(kvs, _) => {
+                    for Property { key: k, value: v, span } in kvs {
+                        match measure.meta.get(k.as_str()) {
+                            Some((prev, prev_span)) if *prev != v => diagnostics.push(
+                                Diagnostic::error("E1601", format!("Meta mismatch in measure {}: `{}` declared twice with different values", index, k), *span)
+                                    .with_label(*prev_span, "previously declared here")
+                            ),
+                            Some(_) => {}
+                            None => { measure.meta.insert(k, (v, *span)); }
                         }
-                    },
-                    _ => {}
+                    }
                 }
             }
         }
     }
 
+    // 4. Linearization in index order. Each assignment starts at its measure's tick.
+    let mut measure_start = 0;
+    let mut prev_index = measures.keys().next().map_or(0, |&first| first.min(1) - 1);
+    for (&index, measure) in &measures {
+        // Missing measures in between are silent bars in the sticky signature
+        for gap in prev_index + 1..index {
+            let length_ticks = time_sig.ticks(ppq);
+            timeline.measures.insert(gap, MeasureInfo { start_tick: measure_start, length_ticks, time_signature: time_sig, ..Default::default() });
+            measure_start += length_ticks;
+        }
+
+        if let Some((value, _)) = measure.meta.get("time") {
+            time_sig = parse_time_signature(value).unwrap_or(time_sig);
+        }
+        // Spec 11.5: an anacrusis only holds its declared pickup duration
+        let pickup = measure.meta.get("pickup")
+            .and_then(|(value, span)| parse_duration_meta("pickup", value, ppq, *span, &mut diagnostics));
+        let capacity = pickup.unwrap_or_else(|| time_sig.ticks(ppq));
+        let volta = match measure.meta.get("volta") {
+            Some((value, span)) => parse_volta(value).unwrap_or_else(|| {
+                diagnostics.push(
+                    Diagnostic::error("E4002", "Invalid type cast: `volta` expects passes such as \"1.\" or \"1,2.\"", *span)
+                );
+                Vec::new()
+            }),
+            None => Vec::new(),
+        };
+        let structure = measure_structure(index, &measure.assignments, &mut diagnostics);
+        timeline.measures.insert(index, MeasureInfo {
+            start_tick: measure_start,
+            length_ticks: capacity,
+            time_signature: time_sig,
+            volta,
+            ..structure
+        });
+
+        // Spec 14.3: static changes or ramps spanning this measure
+        if let Some((value, span)) = measure.meta.get("tempo") {
+            let curve = ramp_curve(measure.meta.get("curve").map(|&(v, _)| v), true);
+            match (value, curve) {
+                (Value::Array(pair), Some(curve)) if pair.len() == 2 => match (number(&pair[0]), number(&pair[1])) {
+                    (Some(from), Some(to)) if bpm_ok(from) && bpm_ok(to) => {
+                        timeline.tempo_map.ramp(measure_start, measure_start + capacity, from, to, curve);
+                    }
+                    _ => diagnostics.push(Diagnostic::error("E4002", "Invalid type cast: tempo ramps need two positive numbers", *span)),
+                },
+                (_, None) => diagnostics.push(
+                    Diagnostic::error("E4002", "Invalid type cast: unknown tempo curve", *span)
+                        .with_help("Use \"step\", \"linear\", \"exp\" or \"log\"")
+                ),
+                (v, Some(_)) => if let Some(bpm) = parse_bpm(v, *span, &mut diagnostics) {
+                    timeline.tempo_map.set(measure_start, bpm);
+                },
+            }
+        }
+
+        let mut lengths = Vec::with_capacity(measure.assignments.len());
+        for stmt in &measure.assignments {
+            let Statement::Assignment { staff_id, voices, span, .. } = stmt else { continue };
+            let Some(track) = timeline.tracks.get_mut(staff_id) else {
+                diagnostics.push(
+                    Diagnostic::error("E2001", format!("Undefined staff `{}`", staff_id), *span)
+                        .with_help(format!("Register it first, e.g. `def {} \"Label\"`", staff_id))
+                );
+                continue;
+            };
+            let cursor = cursors.get_mut(staff_id).unwrap();
+
+            // `|`-separated segments continue the same stream
+            cursor.current_tick = measure_start;
+            cursor.multiplied = false;
+            for voice in voices {
+                process_voice(voice, cursor, track, &mut diagnostics);
+            }
+            lengths.push((staff_id, cursor.current_tick - measure_start, cursor.multiplied, *span));
+        }
+
+        // Spec 14.1: `vln.vol: [1.0, 0.0]` automates across this measure
+        let mut staff_meta: Vec<_> = measure.meta.iter().filter(|(k, _)| k.contains('.')).collect();
+        staff_meta.sort_by_key(|(k, _)| **k);
+        let scope = MixerScope {
+            ticks: measure_start..measure_start + capacity,
+            curve: ramp_curve(measure.meta.get("curve").map(|&(v, _)| v), false),
+            global: false,
+        };
+        for (key, (value, span)) in staff_meta {
+            mixer_meta(&mut timeline, key, value, &scope, *span, &mut diagnostics);
+        }
+
+        // Spec 7.3.1: every staff waits out the fermata, however it is written
+        let factor = match measure.meta.get("fermata") {
+            Some((value, span)) => parse_fermata(value, *span, &mut diagnostics).unwrap_or(fermata),
+            None => fermata,
+        };
+        for cursor in cursors.values_mut() {
+            for (tick, ticks) in cursor.fermatas.drain(..) {
+                let hold = (ticks as f64 * (factor - 1.0)).round() as u64;
+                let entry = timeline.holds.entry(tick).or_default();
+                *entry = (*entry).max(hold);
+            }
+        }
+
+        // Spec 5.1.2: a block stretched to whole bars by a multiplier
+        // (`r:1 * 4`) spans the following bars, provided none is written out
+        let longest = lengths.iter().map(|&(_, length, _, _)| length).max().unwrap_or(0);
+        let spanned = longest / capacity.max(1);
+        let bars = if pickup.is_none() && longest > capacity
+            && lengths.iter().all(|&(_, length, multiplied, _)| length <= capacity || (multiplied && length % capacity == 0))
+            && measures.range(index + 1..index + spanned as i64).next().is_none()
+        {
+            spanned
+        } else {
+            1
+        };
+
+        for (staff_id, length, multiplied, span) in lengths {
+            if pickup.is_some() && length != capacity {
+                diagnostics.push(Diagnostic::warning(
+                    "W3005",
+                    format!("Pickup mismatch: `{}` lasts {} but the declared pickup is {}", staff_id, describe_ticks(length, ppq), describe_ticks(capacity, ppq)),
+                    span,
+                ));
+            } else if length > capacity * bars {
+                let help = if multiplied && length % capacity == 0 && pickup.is_none() {
+                    format!("A multi-bar block needs the next measure to be numbered {} or later", index + (length / capacity) as i64)
+                } else {
+                    format!("Remove {} of events or change the time signature", describe_ticks(length - capacity, ppq))
+                };
+                diagnostics.push(
+                    Diagnostic::error(
+                        "E3001",
+                        format!("Time overflow: `{}` lasts {} in measure {} but the time signature holds {}/{}", staff_id, describe_ticks(length, ppq), index, time_sig.numerator, time_sig.denominator),
+                        span,
+                    )
+                    .with_help(help)
+                );
+            }
+        }
+
+        // Bars covered by a multi-bar block
+        for bar in 1..bars {
+            let start_tick = measure_start + bar * capacity;
+            timeline.measures.insert(index + bar as i64, MeasureInfo { start_tick, length_ticks: capacity, time_signature: time_sig, ..Default::default() });
+        }
+        prev_index = index + bars as i64 - 1;
+        measure_start += capacity * bars;
+    }
+
+    let mut pending: Vec<_> = cursors.iter_mut().filter(|(_, cursor)| !cursor.graces.is_empty()).collect();
+    pending.sort_by(|a, b| a.0.cmp(b.0));
+    for (staff_id, cursor) in pending {
+        orphan_graces(&timeline.tracks[staff_id], cursor, &mut diagnostics);
+    }
+
     // Sort events by tick (since multi-voice processing implies out-of-order insertion)
     for track in timeline.tracks.values_mut() {
         track.events.sort_by_key(|e| e.tick);
     }
 
+    if diagnostics.iter().any(Diagnostic::is_error) {
+        return Err(diagnostics);
+    }
+    timeline.warnings = diagnostics;
     Ok(timeline)
 }
 
+/// Spec 4.6: `keyswitch={ arco: 24, ... }`. Notes outside 0-127 are clamped.
+fn parse_keyswitches(value: &Value, span: Span, diagnostics: &mut Vec<Diagnostic>) -> HashMap<String, u8> {
+    let Value::Map(pairs) = value else {
+        diagnostics.push(Diagnostic::error("E4002", "Invalid type cast: `keyswitch` expects a map such as `{ arco: 24 }`", span));
+        return HashMap::new();
+    };
+    let mut keyswitches = HashMap::new();
+    for (name, note) in pairs {
+        match number(note) {
+            Some(n) => {
+                if !(0.0..=127.0).contains(&n) {
+                    diagnostics.push(Diagnostic::warning("W4003", format!("Value out of range: keyswitch `{}` note {} clamped to 0-127", name, n), span));
+                }
+                keyswitches.insert(name.clone(), n.clamp(0.0, 127.0).round() as u8);
+            }
+            None => diagnostics.push(Diagnostic::error("E4002", format!("Invalid type cast: keyswitch `{}` expects a MIDI note number", name), span)),
+        }
+    }
+    keyswitches
+}
+
+/// Whether `events` contain tab coordinates, including nested blocks.
+fn has_tab(events: &[AstEvent]) -> bool {
+    events.iter().any(|event| match event {
+        AstEvent::Tab { .. } => true,
+        AstEvent::Tuplet { content, .. } | AstEvent::Transposed { content, .. } => has_tab(&content.events),
+        AstEvent::VoiceGroup { voices, .. } => voices.iter().any(|v| has_tab(&v.events)),
+        _ => false,
+    })
+}
+
+/// Spec 4.2: reads `style` and, for tablature, `tuning` and `capo`.
+/// `span` is the def itself, for problems no single value carries.
+fn parse_style(id: &str, attributes: &[Property], span: Span, diagnostics: &mut Vec<Diagnostic>) -> Style {
+    let get = |key: &str| attributes.iter().find(|p| p.key == key);
+    match get("style").map(|p| &p.value) {
+        Some(Value::Id(style)) if style == "tab" => {}
+        Some(Value::Id(style)) if style == "grid" => {
+            return Style::Grid { map: get("map").map_or_else(gm_kit, |p| parse_drum_map(&p.value, p.span, diagnostics)) };
+        }
+        _ => return Style::Standard,
+    }
+
+    let tuning = match get("tuning") {
+        Some(p) => parse_tuning(&p.value).unwrap_or_else(|| {
+            diagnostics.push(Diagnostic::error("E4002", "Invalid type cast: `tuning` expects a pitch array or a standard tuning name", p.span));
+            Vec::new()
+        }),
+        None => {
+            diagnostics.push(
+                Diagnostic::warning("W4003", format!("Value out of range: tab staff `{}` has no tuning; assuming `guitar_std`", id), span)
+                    .with_help("Add e.g. `tuning=guitar_std` or `tuning=[E2, A2, D3, G3, B3, E4]`")
+            );
+            standard_tuning("guitar_std").unwrap()
+        }
+    };
+
+    let capo = match get("capo") {
+        None => 0,
+        Some(p) => match number(&p.value) {
+            Some(n) if n >= 0.0 => n.min(u8::MAX as f64) as u8,
+            _ => {
+                diagnostics.push(Diagnostic::error("E4002", "Invalid type cast: `capo` expects a fret number", p.span));
+                0
+            }
+        },
+    };
+    // Spec 15.3: frets already name the sounding pitch
+    if let Some(p) = get("transpose").filter(|p| number(&p.value).is_some_and(|n| n != 0.0)) {
+        diagnostics.push(
+            Diagnostic::warning("W4003", format!("Value out of range: `transpose` ignored on tab staff `{}`, which sounds the frets as written", id), p.span)
+                .with_help("Change the `tuning` or `capo` instead")
+        );
+    }
+    Style::Tab { tuning, capo }
+}
+
+fn gm_kit() -> HashMap<String, DrumKey> {
+    GM_KIT.iter().map(|&(key, position, note)| (key.to_string(), DrumKey { position, note })).collect()
+}
+
+/// Spec 4.4: `gm_kit` or `{ k: [position, midi_note], ... }`.
+fn parse_drum_map(value: &Value, span: Span, diagnostics: &mut Vec<Diagnostic>) -> HashMap<String, DrumKey> {
+    let pairs = match value {
+        Value::Id(name) if name == "gm_kit" || name == "gm_std" => return gm_kit(),
+        Value::Map(pairs) => pairs,
+        _ => {
+            diagnostics.push(Diagnostic::error("E4002", "Invalid type cast: `map` expects `gm_kit` or a map such as `{ k: [0, 36] }`", span));
+            return HashMap::new();
+        }
+    };
+    let mut map = HashMap::new();
+    for (key, entry) in pairs {
+        let (position, note) = match entry {
+            Value::Array(pair) if pair.len() == 2 => (number(&pair[0]), number(&pair[1])),
+            _ => (None, None),
+        };
+        let (Some(position), Some(note)) = (position, note) else {
+            diagnostics.push(Diagnostic::error("E4002", format!("Invalid type cast: map key `{}` expects `[position, midi_note]`", key), span));
+            continue;
+        };
+        if !(0.0..=127.0).contains(&note) {
+            diagnostics.push(Diagnostic::warning("W4003", format!("Value out of range: map key `{}` note {} clamped to 0-127", key, note), span));
+        }
+        map.insert(key.clone(), DrumKey { position: position as i64, note: note.clamp(0.0, 127.0).round() as u8 });
+    }
+    map
+}
+
+/// Spec 4.3: `channel=1` through `channel=16`.
+fn parse_channel(attributes: &[Property], diagnostics: &mut Vec<Diagnostic>) -> Option<u8> {
+    let Property { value, span, .. } = attributes.iter().find(|p| p.key == "channel")?;
+    let span = *span;
+    let Some(n) = number(value) else {
+        diagnostics.push(Diagnostic::error("E4002", "Invalid type cast: `channel` expects a number from 1 to 16", span));
+        return None;
+    };
+    if !(1.0..=16.0).contains(&n) {
+        diagnostics.push(Diagnostic::warning("W4003", format!("Value out of range: channel {} clamped to 1-16", n), span));
+    }
+    Some(n.clamp(1.0, 16.0).round() as u8)
+}
+
+/// Whether a name lexed as a pitch literal rather than an identifier.
+fn is_pitch(name: &str) -> bool {
+    matches!(Token::lexer(name).next(), Some(Ok(Token::PitchLit(p))) if p.len() == name.len())
+}
+
+/// `guitar_std` or `[E2, A2, ...]`.
+fn parse_tuning(value: &Value) -> Option<Vec<u8>> {
+    match value {
+        Value::Id(name) => standard_tuning(name),
+        Value::Array(pitches) => pitches.iter().map(|p| match p {
+            Value::Id(s) if is_pitch(s) => Some(Cursor::new(1920).parse_pitch(s)),
+            _ => None,
+        }).collect(),
+        _ => None,
+    }
+}
+
+/// Spec 21.1-21.2: `.cc(11, 64)` or `.cc(11, [0, 100], "exp")` on the
+/// event at `first`; chords carry them on their first note. Ramps span
+/// the event's notated length.
+fn attach_controls(track: &mut Track, first: usize, attributes: &[Attribute], ticks: u64, diagnostics: &mut Vec<Diagnostic>) {
+    let mut controls = Vec::new();
+    for attr in attributes.iter().filter(|a| a.name == "cc") {
+        let (number, value, curve) = match attr.args.as_slice() {
+            [number, value] => (number, value, None),
+            [number, value, curve] => (number, value, Some(curve)),
+            _ => {
+                diagnostics.push(
+                    Diagnostic::error("E4002", "Invalid type cast: `.cc` expects a controller and a value", attr.span)
+                        .with_help("e.g. `.cc(11, 64)` or `.cc(11, [0, 100], \"exp\")`")
+                );
+                continue;
+            }
+        };
+        let Some(curve) = ramp_curve(curve, false) else {
+            diagnostics.push(
+                Diagnostic::error("E4002", "Invalid type cast: unknown automation curve", attr.span)
+                    .with_help("Use \"step\", \"linear\", \"exp\" or \"log\"")
+            );
+            continue;
+        };
+        let Some(controller) = ranged(number, "controller", 0.0..=127.0, attr.span, diagnostics) else { continue };
+        let (from, to, end_tick) = match value {
+            Value::Array(pair) if pair.len() == 2 => {
+                let from = ranged(&pair[0], "cc value", 0.0..=127.0, attr.span, diagnostics);
+                let to = ranged(&pair[1], "cc value", 0.0..=127.0, attr.span, diagnostics);
+                let (Some(from), Some(to)) = (from, to) else { continue };
+                (from, to, ticks)
+            }
+            v => match ranged(v, "cc value", 0.0..=127.0, attr.span, diagnostics) {
+                Some(level) => (level, level, 0),
+                None => continue,
+            },
+        };
+        controls.push(Automation { controller: controller.round() as u8, start_tick: 0, end_tick, from, to, curve });
+    }
+    if let Some(event) = track.events.get_mut(first) {
+        event.controls = controls;
+    }
+}
+
+/// Sends `key` just ahead of the event at the cursor so the articulation
+/// is selected before the note sounds. It sits on the note's tick, so it
+/// stays in the note's bar, and plays early through `offset_ticks`.
+fn push_keyswitch(track: &mut Track, cursor: &Cursor, key: u8, span: Span) {
+    let lead = (cursor.ppq as u64 / 64).max(1);
+    track.events.push(AtomicEvent {
+        tick: cursor.current_tick,
+        duration_ticks: 0,
+        sounding_ticks: lead,
+        kind: EventKind::KeySwitch { pitch: key },
+        voice: cursor.voice,
+        tab: None,
+        tie: None,
+        grace: None,
+        offset_ticks: -(lead as i64),
+        controls: Vec::new(),
+        span,
+    });
+}
+
+/// Registers the notes pushed from `first` on. Grace notes wait for their
+/// principal; anything else places the waiting graces and links ties.
+fn attack(track: &mut Track, cursor: &mut Cursor, first: usize, grace: Option<(Grace, bool)>, diagnostics: &mut Vec<Diagnostic>) {
+    let last = track.events.len();
+    match grace {
+        Some((kind, before_beat)) => {
+            for event in &mut track.events[first..] {
+                event.grace = Some(kind);
+                event.tie = None;
+                event.sounding_ticks = grace_length(cursor.ppq);
+            }
+            cursor.graces.push(PendingGrace { events: (first..last).collect(), before_beat });
+        }
+        None => {
+            place_graces(track, cursor, first);
+            link_ties(track, cursor, first, diagnostics);
+            if first < last {
+                cursor.previous = (first..last).collect();
+            }
+        }
+    }
+}
+
+/// Playback length of a grace note: a 32nd.
+fn grace_length(ppq: u32) -> u64 {
+    (ppq as u64 / 8).max(1)
+}
+
+/// Spec 5.4: plays the waiting grace notes. `:grace.slash` ends on the beat
+/// and cuts the previous notes short; the others play on the beat and delay
+/// the principal notes from `first` on. A grace never takes more than half
+/// of the principal.
+fn place_graces(track: &mut Track, cursor: &mut Cursor, first: usize) {
+    if cursor.graces.is_empty() { return; }
+    let beat = cursor.current_tick;
+    let length = grace_length(cursor.ppq);
+    let has_previous = !cursor.previous.is_empty();
+    let (before, on): (Vec<_>, Vec<_>) = std::mem::take(&mut cursor.graces).into_iter()
+        .partition(|g| g.before_beat && has_previous);
+    // A grace left at the end of an underfull bar waits for the next one
+    for grace in before.iter().chain(&on) {
+        for &i in &grace.events {
+            track.events[i].tick = beat;
+        }
+    }
+
+    if !before.is_empty() {
+        let n = before.len() as u64;
+        let step = length.min(beat / n).max(1);
+        let start = beat.saturating_sub(n * step);
+        for (j, grace) in before.iter().enumerate() {
+            for &i in &grace.events {
+                track.events[i].offset_ticks = (start + j as u64 * step) as i64 - beat as i64;
+                track.events[i].sounding_ticks = step;
+            }
+        }
+        for &i in &cursor.previous {
+            let event = &mut track.events[i];
+            let onset = event.tick as i64 + event.offset_ticks;
+            if onset + event.sounding_ticks as i64 > start as i64 {
+                event.sounding_ticks = (start as i64 - onset).max(1) as u64;
+            }
+        }
+    }
+
+    if !on.is_empty() {
+        let n = on.len() as u64;
+        let principal = track.events.get(first).map_or(2 * n * length, |e| e.sounding_ticks);
+        let step = length.min(principal / (2 * n)).max(1);
+        for (j, grace) in on.iter().enumerate() {
+            for &i in &grace.events {
+                track.events[i].offset_ticks = (j as u64 * step) as i64;
+                track.events[i].sounding_ticks = step;
+            }
+        }
+        for event in &mut track.events[first..] {
+            event.offset_ticks += (n * step) as i64;
+            event.sounding_ticks = event.sounding_ticks.saturating_sub(n * step).max(1);
+        }
+    }
+}
+
+/// Warns about grace notes still waiting when their voice ends. They keep
+/// their own tick, as nothing follows them to lean on.
+fn orphan_graces(track: &Track, cursor: &mut Cursor, diagnostics: &mut Vec<Diagnostic>) {
+    for grace in cursor.graces.drain(..) {
+        let Some(&i) = grace.events.first() else { continue };
+        diagnostics.push(
+            Diagnostic::warning("W3008", "Orphaned grace note: no principal note follows it in this voice", track.events[i].span)
+                .with_help("Write the note it ornaments after it, in the same voice")
+        );
+    }
+}
+
+/// Spec 6.6: continues the ties left open by the previous event with the
+/// matching notes pushed from `first` on, then opens ties for new `~` notes.
+/// A tie with no matching pitch is dropped, and the chain ends where its
+/// last note's own articulation gates it.
+fn link_ties(track: &mut Track, cursor: &mut Cursor, first: usize, diagnostics: &mut Vec<Diagnostic>) {
+    for open in std::mem::take(&mut cursor.ties) {
+        let next = (first..track.events.len()).find(|&i| {
+            let event = &track.events[i];
+            matches!(event.tie, None | Some(Tie::Start))
+                && matches!(event.kind, EventKind::Note { pitch, .. } if pitch == open.pitch)
+        });
+        let Some(i) = next else {
+            let last = &mut track.events[open.last];
+            last.tie = (open.last != open.head).then_some(Tie::Stop);
+            diagnostics.push(
+                Diagnostic::warning("W3007", format!("Broken tie: the next event does not repeat MIDI pitch {}", open.pitch), open.span)
+                    .with_help("Ties join notes of the same pitch; use a slur to connect different pitches")
+            );
+            continue;
+        };
+
+        let event = &mut track.events[i];
+        let ongoing = event.tie == Some(Tie::Start);
+        event.tie = Some(if ongoing { Tie::Continue } else { Tie::Stop });
+        let end = event.tick as i64 + event.offset_ticks + event.sounding_ticks as i64;
+        let head = &mut track.events[open.head];
+        head.sounding_ticks = (end - head.tick as i64 - head.offset_ticks).max(1) as u64;
+        if ongoing {
+            cursor.ties.push(OpenTie { last: i, ..open });
+        }
+    }
+
+    for (i, event) in track.events.iter().enumerate().skip(first) {
+        if let (Some(Tie::Start), EventKind::Note { pitch, .. }) = (event.tie, &event.kind) {
+            cursor.ties.push(OpenTie { pitch: *pitch, head: i, last: i, span: event.span });
+        }
+    }
+}
+
+/// Spec 9.1: triggers the mapped instruments for `keys` together and
+/// advances the cursor once.
+fn push_hits(keys: &[String], duration: Option<&Duration>, attributes: &[Attribute], span: Span, cursor: &mut Cursor, track: &mut Track, diagnostics: &mut Vec<Diagnostic>) {
+    let grace = grace_kind(duration);
+    let ticks = cursor.parse_duration(duration);
+    let velocity = cursor.velocity(attributes, diagnostics);
+    let first = track.events.len();
+    for key in keys {
+        let drum = match &track.style {
+            Style::Grid { map } => map.get(key).copied(),
+            _ => None,
+        };
+        let Some(drum) = drum else {
+            let help = match &track.style {
+                Style::Grid { map } => {
+                    let mut keys: Vec<&str> = map.keys().map(String::as_str).collect();
+                    keys.sort_unstable();
+                    format!("Defined keys: {}", keys.join(", "))
+                }
+                _ => format!("`{}` is only valid on a `style=grid` staff", key),
+            };
+            diagnostics.push(
+                Diagnostic::error("E4004", format!("Invalid percussion key `{}`", key), span).with_help(help)
+            );
+            continue;
+        };
+        track.events.push(AtomicEvent {
+            tick: cursor.current_tick,
+            duration_ticks: ticks,
+            sounding_ticks: gate_ticks(attributes, ticks),
+            kind: EventKind::Note { pitch: drum.note, velocity },
+            voice: cursor.voice,
+            tab: None,
+            tie: None,
+            grace: None,
+            offset_ticks: 0,
+            controls: Vec::new(),
+            span,
+        });
+    }
+    attack(track, cursor, first, grace, diagnostics);
+    attach_controls(track, first, attributes, ticks, diagnostics);
+    cursor.fermata(attributes, ticks);
+    cursor.current_tick += ticks;
+}
+
 /// Recursively processes events (supports Tuplets)
-fn process_voice(voice: &Voice, cursor: &mut Cursor, track: &mut Track) {
+fn process_voice(voice: &Voice, cursor: &mut Cursor, track: &mut Track, diagnostics: &mut Vec<Diagnostic>) {
     for event in &voice.events {
+        let grid = matches!(track.style, Style::Grid { .. });
+        // Spec 5.1: `:0` has no length; the sticky duration stands in for it
+        if let Some(d) = event.duration().filter(|&d| grace_kind(Some(d)).is_none() && cursor.length(&d.text).is_none()) {
+            diagnostics.push(
+                Diagnostic::error("E4002", format!("Invalid type cast: `{}` is not a note value the time engine can hold", d.text), d.span)
+                    .with_help("Use a positive value such as `:4`, `:8.` or `:3/8`")
+            );
+        }
         match event {
-            AstEvent::Note { pitch, duration, .. } => {
+            // Spec 4.2.3: on a grid staff every event token is a map key, even
+            // one that lexes as a pitch (`c`, `cb`)
+            AstEvent::Note { pitch, duration, attributes, span, .. } if grid => {
+                push_hits(std::slice::from_ref(pitch), duration.as_ref(), attributes, *span, cursor, track, diagnostics);
+            },
+            AstEvent::Chord { notes, duration, attributes, span, .. } if grid => {
+                push_hits(notes, duration.as_ref(), attributes, *span, cursor, track, diagnostics);
+            },
+            AstEvent::Note { pitch, duration, attributes, tie, span } => {
+                let grace = grace_kind(duration.as_ref());
                 let ticks = cursor.parse_duration(duration.as_ref());
                 let midi = cursor.parse_pitch(pitch);
+                let velocity = cursor.velocity(attributes, diagnostics);
+                if let Some(key) = cursor.technique(attributes, &track.keyswitches) {
+                    push_keyswitch(track, cursor, key, *span);
+                }
+                let first = track.events.len();
                 track.events.push(AtomicEvent {
                     tick: cursor.current_tick,
                     duration_ticks: ticks,
-                    kind: EventKind::Note { pitch: midi, velocity: 100 },
+                    // `link_ties` holds a tied note into the next one
+                    sounding_ticks: gate_ticks(attributes, ticks),
+                    kind: EventKind::Note { pitch: midi, velocity },
+                    voice: cursor.voice,
+                    tab: None,
+                    tie: tie.then_some(Tie::Start),
+                    grace: None,
+                    offset_ticks: 0,
+                    controls: Vec::new(),
+                    span: *span,
                 });
+                attack(track, cursor, first, grace, diagnostics);
+                attach_controls(track, first, attributes, ticks, diagnostics);
+                cursor.fermata(attributes, ticks);
                 cursor.current_tick += ticks;
             },
-            AstEvent::Chord { notes, duration, .. } => {
+            AstEvent::Chord { notes, ties, duration, attributes, span } => {
+                let grace = grace_kind(duration.as_ref());
                 let ticks = cursor.parse_duration(duration.as_ref());
+                let velocity = cursor.velocity(attributes, diagnostics);
+                let sounding = gate_ticks(attributes, ticks);
+                if let Some(key) = cursor.technique(attributes, &track.keyswitches) {
+                    push_keyswitch(track, cursor, key, *span);
+                }
                 // Chords: Multiple notes at SAME cursor tick
-                for note in notes {
+                let first = track.events.len();
+                for (note, tie) in notes.iter().zip(ties) {
+                    if !is_pitch(note) {
+                        diagnostics.push(
+                            Diagnostic::error("E1001", format!("Malformed token: `{}` is not a pitch", note), *span)
+                                .with_help("Percussion keys are only valid on a `style=grid` staff")
+                        );
+                        continue;
+                    }
                     let midi = cursor.parse_pitch(note);
                     track.events.push(AtomicEvent {
                         tick: cursor.current_tick,
                         duration_ticks: ticks,
-                        kind: EventKind::Note { pitch: midi, velocity: 100 },
+                        sounding_ticks: sounding,
+                        kind: EventKind::Note { pitch: midi, velocity },
+                        voice: cursor.voice,
+                        tab: None,
+                        tie: tie.then_some(Tie::Start),
+                        grace: None,
+                        offset_ticks: 0,
+                        controls: Vec::new(),
+                        span: *span,
                     });
                 }
+                attack(track, cursor, first, grace, diagnostics);
                 // Only advance cursor once per chord
+                attach_controls(track, first, attributes, ticks, diagnostics);
+                cursor.fermata(attributes, ticks);
                 cursor.current_tick += ticks;
             },
-            AstEvent::Rest { duration } => {
+            AstEvent::Tab { fret, string, duration, attributes, span } => {
+                let grace = grace_kind(duration.as_ref());
+                let ticks = cursor.parse_duration(duration.as_ref());
+                // Tab events outside a tab staff resolve against the default tuning
+                let (tuning, capo) = match &track.style {
+                    Style::Tab { tuning, capo } => (tuning.clone(), *capo),
+                    _ => {
+                        // Once per staff: later tab events have already been flagged
+                        if track.events.iter().all(|e| e.tab.is_none()) {
+                            diagnostics.push(
+                                Diagnostic::warning("W4003", "Value out of range: tab event on a non-tab staff; assuming `guitar_std`", *span)
+                                    .with_help("Declare the staff with `style=tab` and a `tuning`")
+                            );
+                        }
+                        (standard_tuning("guitar_std").unwrap(), 0)
+                    }
+                };
+                // Spec 8.1: string 1 is the last (highest) entry of the tuning
+                let open = (*string as usize).checked_sub(1)
+                    .and_then(|i| tuning.len().checked_sub(i + 1))
+                    .map(|i| tuning[i]);
+                let Some(open) = open else {
+                    diagnostics.push(
+                        Diagnostic::error("E801", format!("Range error: string {} does not exist on a {}-string tuning", string, tuning.len()), *span)
+                            .with_help(format!("Use a string between 1 and {}", tuning.len()))
+                    );
+                    cursor.current_tick += ticks;
+                    continue;
+                };
+
+                // Spec 8.3: `.p` is a pull-off here, not a dynamic
+                let dynamics: Vec<Attribute> = attributes.iter().filter(|a| a.name != "p").cloned().collect();
+                let velocity = cursor.velocity(&dynamics, diagnostics);
+                if let Some(key) = cursor.technique(attributes, &track.keyswitches) {
+                    push_keyswitch(track, cursor, key, *span);
+                }
+                let pitch = (open as u64 + capo as u64 + *fret as u64).min(127) as u8;
+                let first = track.events.len();
+                track.events.push(AtomicEvent {
+                    tick: cursor.current_tick,
+                    duration_ticks: ticks,
+                    sounding_ticks: gate_ticks(attributes, ticks),
+                    kind: EventKind::Note { pitch, velocity },
+                    voice: cursor.voice,
+                    tab: Some(TabPosition { fret: *fret, string: *string }),
+                    tie: None,
+                    grace: None,
+                    offset_ticks: 0,
+                    controls: Vec::new(),
+                    span: *span,
+                });
+                attack(track, cursor, first, grace, diagnostics);
+                attach_controls(track, first, attributes, ticks, diagnostics);
+                cursor.fermata(attributes, ticks);
+                cursor.current_tick += ticks;
+            },
+            // Spec 23.3: `r` is the ride wherever the staff's map defines it
+            AstEvent::Rest { duration, span } if matches!(&track.style, Style::Grid { map } if map.contains_key("r")) => {
+                push_hits(&["r".to_string()], duration.as_ref(), &[], *span, cursor, track, diagnostics);
+            },
+            AstEvent::Rest { duration, .. } => {
+                attack(track, cursor, track.events.len(), None, diagnostics);
                 let ticks = cursor.parse_duration(duration.as_ref());
                 cursor.current_tick += ticks;
             },
-            AstEvent::Tuplet { content, p, q } => {
+            AstEvent::Tuplet { content, p, q, span } => {
                 // Spec 5.3: "Play P notes in the time of Q"
                 // Scalar = Q / P
                 let old_scalar = cursor.time_scalar;
                 let scale_factor = Rational::new(*q, *p);
                 
                 // Update scalar: New = Old * (Q/P)
-                cursor.time_scalar = Rational::new(
-                    old_scalar.num * scale_factor.num,
-                    old_scalar.den * scale_factor.den
-                );
+                match old_scalar.checked_mul(scale_factor) {
+                    Some(scalar) => cursor.time_scalar = scalar,
+                    None => diagnostics.push(
+                        Diagnostic::error("E4002", format!("Invalid type cast: tuplet ratio {}/{} is too fine for the time engine", p, q), *span)
+                    ),
+                }
 
-                process_voice(content, cursor, track);
+                process_voice(content, cursor, track, diagnostics);
 
                 // Restore scalar
                 cursor.time_scalar = old_scalar;
             },
-            _ => {} // Tab/Percussion placeholders for now
+            AstEvent::VoiceGroup { voices, span } => {
+                // Spec 10.1: every voice starts at the group's entry tick
+                let start = cursor.current_tick;
+                let mut end = start;
+                // Exact lengths: truncated tuplet ticks would not add up
+                let mut totals: Vec<(u8, Rational, Span)> = Vec::with_capacity(voices.len());
+                for voice in voices {
+                    let id = voice.id.unwrap_or(1);
+                    if let Some((_, _, first)) = totals.iter().find(|(seen, _, _)| *seen == id) {
+                        diagnostics.push(
+                            Diagnostic::error("E2002", format!("Duplicate definition of voice `v{}` in this group", id), voice.span)
+                                .with_label(*first, "first defined here")
+                                .with_help("Give each voice its own id, from v1 to v4")
+                        );
+                        continue;
+                    }
+                    if id == 1 {
+                        // v1 inherits the staff state and leaves its own behind on exit
+                        cursor.current_tick = start;
+                        let outer = std::mem::replace(&mut cursor.elapsed, Rational::new(0, 1));
+                        process_voice(voice, cursor, track, diagnostics);
+                        end = end.max(cursor.current_tick);
+                        totals.push((id, std::mem::replace(&mut cursor.elapsed, outer), voice.span));
+                    } else {
+                        // v2..v4 start from defaults (octave 4, quarter note)
+                        let mut secondary = Cursor::new(cursor.ppq);
+                        secondary.current_tick = start;
+                        secondary.time_scalar = cursor.time_scalar;
+                        secondary.transpose = cursor.transpose;
+                        secondary.voice = id;
+                        // Dynamics are staff state, not voice state
+                        secondary.velocity = cursor.velocity;
+                        secondary.technique = cursor.technique.clone();
+                        process_voice(voice, &mut secondary, track, diagnostics);
+                        orphan_graces(track, &mut secondary, diagnostics);
+                        cursor.fermatas.append(&mut secondary.fermatas);
+                        // Nothing follows the voice for its open ties to reach
+                        for open in secondary.ties.drain(..) {
+                            track.events[open.last].tie = (open.last != open.head).then_some(Tie::Stop);
+                            diagnostics.push(
+                                Diagnostic::warning("W3007", format!("Broken tie: voice v{} ends with MIDI pitch {} still tied", id, open.pitch), open.span)
+                                    .with_help("Ties in v2-v4 must end inside the voice group")
+                            );
+                        }
+                        end = end.max(secondary.current_tick);
+                        totals.push((id, secondary.elapsed, voice.span));
+                    }
+                }
+                cursor.current_tick = end;
+                if let Some(longest) = totals.iter().map(|&(_, total, _)| total).max() {
+                    cursor.elapsed = cursor.elapsed.checked_add(longest).unwrap_or(cursor.elapsed);
+                }
+
+                // Spec 10.3: every voice in the group must fill the same time
+                if totals.iter().any(|(_, total, _)| *total != totals[0].1) {
+                    let summary: Vec<String> = totals.iter()
+                        .map(|(id, total, _)| format!("v{}: {}/{}", id, total.num, total.den))
+                        .collect();
+                    let mut diag = Diagnostic::error("E3002", format!("Voice sync failure: voices last {}", summary.join(", ")), *span)
+                        .with_help("Pad the shorter voices with explicit rests (`r`)");
+                    for (id, total, voice_span) in &totals {
+                        diag = diag.with_label(*voice_span, format!("v{} lasts {}/{}", id, total.num, total.den));
+                    }
+                    diagnostics.push(diag);
+                }
+            },
+            AstEvent::Transposed { content, semitones, span } => {
+                // Spec 15.3: transposing tab coordinates is undefined
+                if *semitones != 0 && has_tab(&content.events) {
+                    diagnostics.push(
+                        Diagnostic::warning("W4003", "Value out of range: transposition ignored, as tab events sound the frets as written", *span)
+                            .with_help("Write the transposed frets out instead")
+                    );
+                }
+                cursor.transpose += semitones;
+                process_voice(content, cursor, track, diagnostics);
+                cursor.transpose -= semitones;
+            },
+            AstEvent::Percussion { key, duration, attributes, span } => {
+                push_hits(std::slice::from_ref(key), duration.as_ref(), attributes, *span, cursor, track, diagnostics);
+            },
+            AstEvent::MacroCall { .. } => {} // Resolved by the pre-processor
         }
     }
 }
\ No newline at end of file
@@ -1,9 +1,13 @@
 use logos::Logos;
+use crate::diagnostic::Diagnostic;
+use crate::span::{FileId, Span};
+use std::fmt;
 
 /// Tenuto Token Definitions
 /// Implements Spec Section 26.1 (Lexical Tokens)
 #[derive(Logos, Debug, PartialEq, Eq, Clone, Hash)]
 #[logos(skip r"[ \t\r\n\f]+")] // Ignore whitespace
+#[logos(skip "\u{FEFF}")]       // Spec 2.1: Ignore the BOM
 #[logos(skip r"%%.*")]          // Spec 2.3: Ignore Line Comments
 pub enum Token {
 
@@ -34,6 +38,8 @@ pub enum Token {
     #[token("|")] Pipe,
     #[token("~")] Tilde,
     #[token("=")] Equals,
+    #[token("==")] EqEq,
+    #[token("!=")] NotEq,
     #[token(",")] Comma,
     #[token(".")] Dot,
     #[token("$")] Dollar,
@@ -72,8 +78,9 @@ pub enum Token {
     // 4. MUSIC PRIMITIVES (High Priority)
     // ========================================================================
 
-    // Duration: :4, :8., :16
-    #[regex(r":[0-9]+(\.)*", |lex| lex.slice().to_string())]
+    // Duration: :4, :8., :16, :0.5 (breve), :3/8, or a grace note: :grace, :grace.slash
+    #[regex(r":[0-9]+", duration_dots)]
+    #[token(":grace", grace_form)]
     DurationLit(String),
 
     // Tab Coordinate: 0-6, 12-2
@@ -94,4 +101,113 @@ pub enum Token {
     // Trap C-style comments to fail gracefully if user confuses syntax
     #[regex(r"//.*", |_| false)] 
     InvalidComment,
-}
\ No newline at end of file
+}
+
+// Dots after a duration are augmentation dots, except the last one when an
+// attribute name follows: `:4.stacc` is a quarter note marked staccato.
+fn duration_dots(lex: &mut logos::Lexer<Token>) -> String {
+    // A fraction (`:3/8`) or decimal (`:0.5`) continues the number
+    let rest = lex.remainder();
+    for sep in ['/', '.'] {
+        let digits = rest.strip_prefix(sep)
+            .map_or(0, |r| r.bytes().take_while(u8::is_ascii_digit).count());
+        if digits > 0 {
+            lex.bump(1 + digits);
+            if sep == '/' { return lex.slice().to_string(); }
+            break;
+        }
+    }
+
+    let rest = lex.remainder();
+    let dots = rest.bytes().take_while(|&b| b == b'.').count();
+    let attribute_follows = rest[dots..].starts_with(|c: char| c.is_ascii_alphabetic() || c == '_');
+    lex.bump(if attribute_follows { dots.saturating_sub(1) } else { dots });
+    lex.slice().to_string()
+}
+
+/// Takes `.slash`/`.noSlash` into a `:grace` duration; any other
+/// `.name` is left as an attribute.
+fn grace_form(lex: &mut logos::Lexer<Token>) -> String {
+    let rest = lex.remainder();
+    for form in [".slash", ".noSlash"] {
+        let Some(after) = rest.strip_prefix(form) else { continue };
+        if !after.starts_with(|c: char| c.is_ascii_alphanumeric() || c == '_') {
+            lex.bump(form.len());
+            break;
+        }
+    }
+    lex.slice().to_string()
+}
+
+/// Lexes `source` into the spanned token stream consumed by the parser.
+/// Malformed input and `//` comments are reported as E1001 and dropped.
+pub fn tokenize(source: &str, file: FileId) -> (Vec<(Token, Span)>, Vec<Diagnostic>) {
+    let mut tokens = Vec::new();
+    let mut errors = Vec::new();
+
+    for (tok, range) in Token::lexer(source).spanned() {
+        let span = Span::new(file, range);
+        match tok {
+            Ok(Token::InvalidComment) | Err(_) => errors.push(lexical_error(source, span)),
+            Ok(t) => tokens.push((t, span)),
+        }
+    }
+    (tokens, errors)
+}
+
+fn lexical_error(source: &str, span: Span) -> Diagnostic {
+    let text = &source[span.range()];
+    if text.starts_with("//") {
+        Diagnostic::error("E1001", "Malformed token: C-style comment", span)
+            .with_help("Tenuto line comments start with `%%`")
+    } else {
+        Diagnostic::error("E1001", format!("Malformed token `{}`", text), span)
+    }
+}
+
+// Human-readable token names for "expected ..." messages.
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::KwTenuto => write!(f, "tenuto"),
+            Token::KwMeta => write!(f, "meta"),
+            Token::KwDef => write!(f, "def"),
+            Token::KwMeasure => write!(f, "measure"),
+            Token::KwGroup => write!(f, "group"),
+            Token::KwImport => write!(f, "import"),
+            Token::KwMacro => write!(f, "macro"),
+            Token::KwVar => write!(f, "var"),
+            Token::KwIf => write!(f, "if"),
+            Token::KwElse => write!(f, "else"),
+            Token::LBrace => write!(f, "{{"),
+            Token::RBrace => write!(f, "}}"),
+            Token::LBracket => write!(f, "["),
+            Token::RBracket => write!(f, "]"),
+            Token::LParen => write!(f, "("),
+            Token::RParen => write!(f, ")"),
+            Token::Colon => write!(f, ":"),
+            Token::Pipe => write!(f, "|"),
+            Token::Tilde => write!(f, "~"),
+            Token::Equals => write!(f, "="),
+            Token::EqEq => write!(f, "=="),
+            Token::NotEq => write!(f, "!="),
+            Token::Comma => write!(f, ","),
+            Token::Dot => write!(f, "."),
+            Token::Dollar => write!(f, "$"),
+            Token::Star => write!(f, "*"),
+            Token::Plus => write!(f, "+"),
+            Token::Minus => write!(f, "-"),
+            Token::Slash => write!(f, "/"),
+            Token::RepeatStart => write!(f, "|:"),
+            Token::RepeatEnd => write!(f, ":|"),
+            Token::RepeatDouble => write!(f, ":|:"),
+            Token::DoubleBar => write!(f, "||"),
+            Token::FinalBar => write!(f, "|]"),
+            Token::Integer(i) => write!(f, "{}", i),
+            Token::Float(s) => write!(f, "{}", s),
+            Token::StringLit(s) => write!(f, "\"{}\"", s),
+            Token::DurationLit(s) | Token::TabLit(s) | Token::PitchLit(s) | Token::Identifier(s) => write!(f, "{}", s),
+            Token::InvalidComment => write!(f, "//"),
+        }
+    }
+}
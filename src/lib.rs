@@ -2,11 +2,20 @@ pub mod lexer;
 pub mod parser;
 pub mod ir;
 pub mod midi;   // <--- Added MIDI module
+pub mod playback;
+pub mod span;
+pub mod diagnostic;
+pub mod loader;
+pub mod preprocess;
 // pub mod binary; // Keeping this commented out or removed if we strictly "rolled back"
 
 use thiserror::Error;
 // ... (rest of file remains the same)
 use num_integer::Integer;
+use diagnostic::{Diagnostic, SourceMap};
+use ir::Timeline;
+use parser::Score;
+use std::path::PathBuf;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct Rational {
@@ -26,6 +35,39 @@ impl Rational {
     pub fn to_ticks(&self, ppq: u32) -> u64 {
         (self.num * 4 * ppq as u64) / self.den
     }
+
+    /// `to_ticks`, or `None` if the result does not fit.
+    pub fn checked_ticks(&self, ppq: u32) -> Option<u64> {
+        u64::try_from(self.num as u128 * 4 * ppq as u128 / self.den as u128).ok()
+    }
+
+    /// `self * other`, or `None` on overflow.
+    pub fn checked_mul(self, other: Self) -> Option<Self> {
+        // Cancel across first so exact products stay small
+        let (a, b) = (self.num.gcd(&other.den).max(1), other.num.gcd(&self.den).max(1));
+        let num = (self.num / a).checked_mul(other.num / b)?;
+        let den = (self.den / b).checked_mul(other.den / a)?;
+        Some(Self::new(num, den))
+    }
+
+    /// `self + other`, or `None` on overflow.
+    pub fn checked_add(self, other: Self) -> Option<Self> {
+        let den = (self.den / self.den.gcd(&other.den)).checked_mul(other.den)?;
+        let num = self.num.checked_mul(den / self.den)?.checked_add(other.num.checked_mul(den / other.den)?)?;
+        Some(Self::new(num, den))
+    }
+}
+
+impl Ord for Rational {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        (self.num as u128 * other.den as u128).cmp(&(other.num as u128 * self.den as u128))
+    }
+}
+
+impl PartialOrd for Rational {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
 }
 
 #[derive(Error, Debug)]
@@ -39,11 +81,63 @@ pub enum TenutoError {
 
 pub struct Pipeline {
     pub source: String,
+    /// Location of `source` on disk; anchors relative `import` paths.
+    pub path: Option<PathBuf>,
     pub strict_mode: bool,
+    /// Environment for `if (target == ...)` blocks.
+    pub target: preprocess::BuildTarget,
+    /// Every file read during compilation, for rendering diagnostics.
+    pub sources: SourceMap,
 }
 
 impl Pipeline {
     pub fn new(source: String) -> Self {
-        Self { source, strict_mode: false }
+        Self { source, path: None, strict_mode: false, target: Default::default(), sources: SourceMap::new() }
+    }
+
+    pub fn from_file(path: impl Into<PathBuf>) -> Result<Self, TenutoError> {
+        let path = path.into();
+        let source = std::fs::read_to_string(&path)?;
+        Ok(Self { path: Some(path), ..Self::new(source) })
+    }
+
+    /// Phases 1-2: Lexes and parses the root file, splicing in every import,
+    /// then runs the pre-processor over the combined score.
+    pub fn parse(&mut self) -> (Option<Score>, Vec<Diagnostic>) {
+        let name = match &self.path {
+            Some(p) => p.display().to_string(),
+            None => "<input>".to_string(),
+        };
+        let mut loader = loader::Loader::new(&mut self.sources, &self.target);
+        let score = loader.load_root(&name, self.source.clone(), self.path.as_deref());
+        let mut diagnostics = loader.diagnostics;
+
+        let score = score.map(|score| {
+            let (score, diags) = preprocess::expand(score, &self.target);
+            diagnostics.extend(diags);
+            score
+        });
+        (score, diagnostics)
     }
-}
\ No newline at end of file
+
+    /// Runs the full front end. Parse warnings are carried into the timeline.
+    pub fn compile(&mut self) -> Result<Timeline, Vec<Diagnostic>> {
+        let (score, mut diagnostics) = self.parse();
+        let score = match score {
+            Some(score) if !diagnostics.iter().any(Diagnostic::is_error) => score,
+            _ => return Err(diagnostics),
+        };
+
+        match ir::compile(score) {
+            Ok(mut timeline) => {
+                diagnostics.append(&mut timeline.warnings);
+                timeline.warnings = diagnostics;
+                Ok(timeline)
+            }
+            Err(errors) => {
+                diagnostics.extend(errors);
+                Err(diagnostics)
+            }
+        }
+    }
+}
@@ -0,0 +1,119 @@
+use crate::diagnostic::{Diagnostic, SourceMap};
+use crate::parser::{self, Score, TopLevel};
+use crate::preprocess::{BuildTarget, Conditions};
+use crate::span::{FileId, Span};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+/// Spec 16.1: Resolves `import` directives by splicing the imported file's
+/// items in place of the directive. Each file is processed exactly once.
+/// Top-level `if` blocks are decided here so only the taken branch is loaded.
+pub struct Loader<'a> {
+    sources: &'a mut SourceMap,
+    /// Registry of every file already processed (Duplicate Guard).
+    loaded: HashSet<PathBuf>,
+    /// Files currently being expanded, outermost first (Cycle Detection).
+    stack: Vec<PathBuf>,
+    conditions: Conditions,
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl<'a> Loader<'a> {
+    pub fn new(sources: &'a mut SourceMap, target: &BuildTarget) -> Self {
+        Self { sources, loaded: HashSet::new(), stack: Vec::new(), conditions: Conditions::new(target), diagnostics: Vec::new() }
+    }
+
+    /// Parses the root source. `path` anchors relative imports; without it
+    /// they resolve against the working directory.
+    pub fn load_root(&mut self, name: &str, source: String, path: Option<&Path>) -> Option<Score> {
+        let canonical = path.and_then(|p| p.canonicalize().ok());
+        if let Some(p) = &canonical {
+            self.loaded.insert(p.clone());
+        }
+        let file = self.sources.add(name, source);
+        let base = path.and_then(Path::parent).map(Path::to_path_buf).unwrap_or_default();
+        self.parse_file(file, canonical, &base)
+    }
+
+    fn parse_file(&mut self, file: FileId, canonical: Option<PathBuf>, base: &Path) -> Option<Score> {
+        let (score, diags) = parser::parse(self.sources.text(file), file);
+        self.diagnostics.extend(diags);
+        let mut score = score?;
+
+        if let Some(p) = &canonical { self.stack.push(p.clone()); }
+
+        score.items = self.splice(score.items, base);
+
+        if canonical.is_some() { self.stack.pop(); }
+        Some(score)
+    }
+
+    fn splice(&mut self, items: Vec<TopLevel>, base: &Path) -> Vec<TopLevel> {
+        let mut out = Vec::with_capacity(items.len());
+        for item in items {
+            match item {
+                TopLevel::Import(rel, span) => out.extend(self.import(&rel, span, base)),
+                // An untaken branch must not claim the duplicate guard or fail on a missing file
+                TopLevel::If { condition, then, otherwise, .. } => {
+                    let branch = if self.conditions.evaluate(&condition, &mut self.diagnostics) { then } else { otherwise };
+                    out.extend(self.splice(branch, base));
+                }
+                TopLevel::Var { name, value, span } => {
+                    self.conditions.declare(&name, &value, span);
+                    out.push(TopLevel::Var { name, value, span });
+                }
+                other => out.push(other),
+            }
+        }
+        out
+    }
+
+    fn import(&mut self, rel: &str, span: Span, base: &Path) -> Vec<TopLevel> {
+        let path = base.join(rel);
+        let canonical = match path.canonicalize() {
+            Ok(p) => p,
+            Err(e) => {
+                self.diagnostics.push(
+                    Diagnostic::error("E2003", format!("Import failure: cannot resolve \"{}\"", rel), span)
+                        .with_help(format!("Looked for {} ({})", path.display(), e))
+                );
+                return Vec::new();
+            }
+        };
+
+        // Spec 16.1: a cycle is broken by skipping the import; Spec 24.3 makes it E2004
+        if let Some(pos) = self.stack.iter().position(|p| *p == canonical) {
+            let chain: Vec<String> = self.stack[pos..].iter()
+                .chain(std::iter::once(&canonical))
+                .map(|p| p.file_name().unwrap_or_default().to_string_lossy().into_owned())
+                .collect();
+            self.diagnostics.push(
+                Diagnostic::error("E2004", format!("Circular import of \"{}\"", rel), span)
+                    .with_help(format!("Dependency loop: {}", chain.join(" -> ")))
+            );
+            return Vec::new();
+        }
+
+        // Duplicate Guard: a shared library is only processed the first time
+        if !self.loaded.insert(canonical.clone()) {
+            return Vec::new();
+        }
+
+        let source = match std::fs::read_to_string(&canonical) {
+            Ok(s) => s,
+            Err(e) => {
+                self.diagnostics.push(
+                    Diagnostic::error("E2003", format!("Import failure: cannot read \"{}\"", rel), span)
+                        .with_help(e.to_string())
+                );
+                return Vec::new();
+            }
+        };
+
+        let file = self.sources.add(path.display().to_string(), source);
+        let dir = canonical.parent().map(Path::to_path_buf).unwrap_or_default();
+        self.parse_file(file, Some(canonical), &dir)
+            .map(|score| score.items)
+            .unwrap_or_default()
+    }
+}
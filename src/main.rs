@@ -1,12 +1,11 @@
 use clap::Parser;
 use std::path::PathBuf;
-use logos::Logos;
-use chumsky::Parser as ChumskyParser; 
-use chumsky::Stream;
-use tenutoc::lexer::Token;
-use tenutoc::parser::parser; 
-use tenutoc::ir; 
+use tenutoc::diagnostic::{Diagnostic, SourceMap};
+use tenutoc::Pipeline;
+use tenutoc::preprocess::BuildTarget;
+use tenutoc::ir;
 use tenutoc::midi; // <--- Import MIDI
+use tenutoc::playback;
 
 #[derive(Parser)]
 #[command(name = "tenutoc")]
@@ -20,62 +19,73 @@ struct Cli {
     /// Output MIDI file (.mid)
     #[arg(short, long, value_name = "OUT")]
     output: Option<PathBuf>,
+
+    /// Build target exposed to `if (target == ...)` blocks
+    #[arg(short, long, default_value = "audio", value_parser = ["score", "audio", "part"])]
+    target: String,
+
+    /// Staff ID exposed as `part_id` (e.g. when rendering a part)
+    #[arg(short, long, value_name = "ID")]
+    part: Option<String>,
+
+    /// Sets the `debug` condition variable
+    #[arg(short, long)]
+    debug: bool,
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let cli = Cli::parse();
-    
+
     println!("🎵 tenutoc v2.0.0");
     println!("Reading {:?}", cli.input);
 
     // 1. Read Source
-    let source = std::fs::read_to_string(&cli.input)
+    let mut pipeline = Pipeline::from_file(&cli.input)
         .map_err(|e| format!("F9001: Could not read file {:?}: {}", cli.input, e))?;
+    pipeline.target = BuildTarget { target: cli.target, part_id: cli.part, debug: cli.debug };
 
-    // 2. Lexical Analysis
-    let lexer = Token::lexer(source.as_str());
-    let token_stream: Vec<(Token, std::ops::Range<usize>)> = lexer.spanned()
-        .map(|(tok, span)| match tok {
-            Ok(t) => (t, span),
-            Err(_) => (Token::InvalidComment, span), 
-        })
-        .filter(|(tok, _)| *tok != Token::InvalidComment) 
-        .collect();
+    // 2. Lexical Analysis & Parsing (imports are resolved here)
+    let (ast, parse_diags) = pipeline.parse();
+    let sources = &pipeline.sources;
+    report(&parse_diags, sources)?;
 
-    println!("✅ Phase 1: Lexing Complete ({} tokens)", token_stream.len());
+    let Some(score) = ast else { std::process::exit(1) };
+    if parse_diags.iter().any(Diagnostic::is_error) { std::process::exit(1) }
+    println!("✅ Phase 1-2: Lexing & Parsing Complete.");
 
-    // 3. Parsing
-    let len = source.chars().count();
-    let eoi = len..len + 1; 
-    let stream = Stream::from_iter(eoi, token_stream.into_iter());
+    // 3. Linearization
+    println!("--- Starting Inference Engine ---");
+    let timeline = match ir::compile(score) {
+        Ok(timeline) => timeline,
+        Err(diags) => {
+            report(&diags, sources)?;
+            std::process::exit(1);
+        }
+    };
+    report(&timeline.warnings, sources)?;
 
-    let (ast, parse_errs) = parser().parse_recovery(stream);
-    for err in parse_errs { println!("❌ Parse Error: {:?}", err); }
+    println!("✅ Phase 3: Linearization Complete.");
+    println!("    Title: {}", timeline.title);
+    println!("    Tempo: {} BPM", timeline.tempo);
 
-    if let Some(score) = ast {
-        println!("✅ Phase 2: Parsing Complete.");
-        
-        // 4. Linearization
-        println!("--- Starting Inference Engine ---");
-        match ir::compile(score) {
-            Ok(timeline) => {
-                println!("✅ Phase 3: Linearization Complete.");
-                println!("    Title: {}", timeline.title);
-                println!("    Tempo: {} BPM", timeline.tempo);
-                
-                // 5. MIDI Export
-                if let Some(out_path) = cli.output {
-                    println!("--- Starting MIDI Encoder ---");
-                    let bytes = midi::export(&timeline)?;
-                    std::fs::write(&out_path, bytes)?;
-                    println!("🎹 Saved MIDI to {:?}", out_path);
-                } else {
-                    println!("ℹ️  No output file specified. Use --output <FILE.mid> to save.");
-                }
-            },
-            Err(e) => eprintln!("🔥 Logic Error: {}", e),
-        }
+    // 4. MIDI Export
+    if let Some(out_path) = cli.output {
+        println!("--- Starting MIDI Encoder ---");
+        // Repeats, fermatas, swing and humanization are played out in the file
+        let bytes = midi::export(&playback::render(&timeline))?;
+        std::fs::write(&out_path, bytes)?;
+        println!("🎹 Saved MIDI to {:?}", out_path);
+    } else {
+        println!("ℹ️  No output file specified. Use --output <FILE.mid> to save.");
     }
 
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Renders diagnostics as colored ariadne reports on stderr.
+fn report(diags: &[Diagnostic], sources: &SourceMap) -> std::io::Result<()> {
+    for diag in diags {
+        diag.eprint(sources)?;
+    }
+    Ok(())
+}
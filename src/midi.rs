@@ -1,5 +1,5 @@
-use crate::ir::{Timeline, EventKind};
-use midly::{Smf, Header, Format, Timing, Track, TrackEvent, TrackEventKind, MidiMessage, MetaMessage};
+use crate::ir::{Automation, Timeline, TempoMap, TimeSignature, EventKind, Style, Tie};
+use midly::{Smf, Header, Format, Timing, TrackEvent, TrackEventKind, MidiMessage, MetaMessage};
 use midly::num::u28;
 
 pub fn export(timeline: &Timeline) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
@@ -24,18 +24,49 @@ pub fn export(timeline: &Timeline) -> Result<Vec<u8>, Box<dyn std::error::Error>
 
     // Tempo: Convert BPM to Microseconds per Quarter Note
     // Formula: 60,000,000 / BPM
-    let mpq = 60_000_000 / timeline.tempo;
-    conductor_track.push(TrackEvent {
-        delta: 0.into(),
-        kind: TrackEventKind::Meta(MetaMessage::Tempo(mpq.into())),
-    });
+    // Ramps are sampled every 32nd note so players follow the curve.
+    let mut conductor_events = Vec::new();
+    let mut last_mpq = None;
+    let map = &timeline.tempo_map;
+    for (i, seg) in map.segments.iter().enumerate() {
+        let step = (map.ppq as u64 / 8).max(1);
+        // A ramp cut short by the next change stops sampling there
+        let end = map.segments.get(i + 1).map_or(seg.end_tick, |next| seg.end_tick.min(next.start_tick));
+        let ticks = (seg.start_tick..end).step_by(step as usize).chain(std::iter::once(end));
+        for tick in ticks {
+            let mpq = (60_000_000.0 / map.bpm_at(tick)).round() as u32;
+            if last_mpq == Some(mpq) { continue; }
+            last_mpq = Some(mpq);
+            conductor_events.push(TempEvent {
+                tick,
+                kind: TrackEventKind::Meta(MetaMessage::Tempo(mpq.into())),
+            });
+        }
+    }
 
-    // End of Track
-    conductor_track.push(TrackEvent {
-        delta: 0.into(),
-        kind: TrackEventKind::Meta(MetaMessage::EndOfTrack),
-    });
+    // Time Signatures: one event wherever the measure grid changes meter.
+    // A bar shorter than its meter (a pickup) is written at its real length
+    // so later bar lines stay aligned.
+    let mut current_sig = None;
+    for measure in timeline.measures.values() {
+        let sig = bar_signature(measure.time_signature, measure.length_ticks, map.ppq);
+        if current_sig == Some(sig) { continue; }
+        current_sig = Some(sig);
 
+        // MIDI stores the denominator as a power of two
+        if !sig.denominator.is_power_of_two() || sig.numerator > u8::MAX as u64 { continue; }
+        conductor_events.push(TempEvent {
+            tick: measure.start_tick,
+            kind: TrackEventKind::Meta(MetaMessage::TimeSignature(
+                sig.numerator as u8,
+                sig.denominator.trailing_zeros() as u8,
+                24, // MIDI clocks per metronome click
+                8,  // 32nd notes per quarter
+            )),
+        });
+    }
+
+    conductor_track.extend(to_delta_track(conductor_events));
     smf.tracks.push(conductor_track);
 
     // 3. Process Instrument Tracks
@@ -43,13 +74,23 @@ pub fn export(timeline: &Timeline) -> Result<Vec<u8>, Box<dyn std::error::Error>
     let mut sorted_keys: Vec<_> = timeline.tracks.keys().collect();
     sorted_keys.sort();
 
-    for (idx, key) in sorted_keys.iter().enumerate() {
-        let tenuto_track = &timeline.tracks[*key];
+    let soloing = timeline.tracks.values().any(|t| t.solo);
+    let mut next_channel: u8 = 0;
+    for key in sorted_keys {
+        let tenuto_track = &timeline.tracks[key];
         let mut midi_events = Vec::new();
         
-        // Channel logic: 0-15. Percussion usually 9 (10 in 1-based).
-        // Simple auto-assignment loop, skipping 9 unless explicitly percussion.
-        let channel = (idx % 16) as u8; 
+        // Channel logic: 0-15. Percussion uses 9 (10 in 1-based).
+        // Explicit `channel=` wins; otherwise auto-assign, skipping 9.
+        let channel = match (tenuto_track.channel, &tenuto_track.style) {
+            (Some(c), _) => c - 1,
+            (None, Style::Grid { .. }) => 9,
+            (None, _) => {
+                let c = next_channel % 15;
+                next_channel += 1;
+                if c >= 9 { c + 1 } else { c }
+            }
+        };
 
         // A. Set Instrument Patch (Program Change)
         // Simple mapping: default to Grand Piano (0) if parsing fails
@@ -62,66 +103,66 @@ pub fn export(timeline: &Timeline) -> Result<Vec<u8>, Box<dyn std::error::Error>
             }
         });
 
-        // B. Explode Note Durations into On/Off pairs
-        for event in &tenuto_track.events {
-            match event.kind {
-                EventKind::Note { pitch, velocity } => {
-                    // Note On
-                    midi_events.push(TempEvent {
-                        tick: event.tick,
-                        kind: TrackEventKind::Midi {
-                            channel: channel.into(),
-                            message: MidiMessage::NoteOn { 
-                                key: pitch.into(), 
-                                vel: velocity.into() 
-                            },
-                        }
-                    });
-
-                    // Note Off (at start + duration)
-                    midi_events.push(TempEvent {
-                        tick: event.tick + event.duration_ticks,
-                        kind: TrackEventKind::Midi {
-                            channel: channel.into(),
-                            message: MidiMessage::NoteOff { 
-                                key: pitch.into(), 
-                                vel: 0.into() 
-                            },
-                        }
-                    });
-                },
-                _ => {} // Rests are implicit in MIDI (gap between events)
-            }
+        // Spec 14.1: mixer automation as controller changes
+        for (i, lane) in tenuto_track.automation.iter().enumerate() {
+            // A ramp cut short by the next change on the same controller stops there
+            let end = tenuto_track.automation[i + 1..].iter()
+                .find(|next| next.controller == lane.controller)
+                .map_or(lane.end_tick, |next| lane.end_tick.min(next.start_tick));
+            midi_events.extend(controller_events(lane, 0, end, channel, map));
         }
 
-        // C. Sort by absolute tick to prepare for Delta calculation
-        midi_events.sort_by(|a, b| a.tick.cmp(&b.tick));
+        // Spec 14.1.3: muted staves, or unsoloed ones while another is soloed, stay silent
+        let audible = !tenuto_track.mute && (!soloing || tenuto_track.solo);
+        let events = if audible { &tenuto_track.events[..] } else { &[] };
 
-        // D. Convert to Delta Time
-        let mut final_track = Vec::new();
-        let mut current_tick = 0;
+        // B. Explode Note Durations into On/Off pairs
+        // (Rests are implicit in MIDI: the gap between events)
+        for event in events {
+            // Grace notes and their principals are displaced from the grid
+            let onset = (event.tick as i64 + event.offset_ticks).max(0) as u64;
+
+            // Spec 21.1: `.cc()` goes out ahead of the note it rides on
+            for control in &event.controls {
+                midi_events.extend(controller_events(control, onset, onset + control.end_tick, channel, map));
+            }
 
-        for e in midi_events {
-            let delta = e.tick - current_tick;
-            
-            // midly uses u28 for deltas. Ensure we don't overflow (unlikely in music).
-            let delta_u28 = u28::from_int_lossy(delta as u32);
+            // Tied continuations are covered by the first note of the chain
+            if matches!(event.tie, Some(Tie::Continue | Tie::Stop)) { continue; }
+            // Keyswitches are ordinary notes to the sampler, sent at minimum velocity
+            let (pitch, velocity) = match event.kind {
+                EventKind::Note { pitch, velocity } => (pitch, velocity),
+                EventKind::KeySwitch { pitch } => (pitch, 1),
+                EventKind::Rest => continue,
+            };
 
-            final_track.push(TrackEvent {
-                delta: delta_u28,
-                kind: e.kind,
+            // Note On
+            midi_events.push(TempEvent {
+                tick: onset,
+                kind: TrackEventKind::Midi {
+                    channel: channel.into(),
+                    message: MidiMessage::NoteOn { 
+                        key: pitch.into(), 
+                        vel: velocity.into() 
+                    },
+                }
             });
 
-            current_tick = e.tick;
+            // Note Off (at start + sounding duration)
+            midi_events.push(TempEvent {
+                tick: onset + event.sounding_ticks,
+                kind: TrackEventKind::Midi {
+                    channel: channel.into(),
+                    message: MidiMessage::NoteOff { 
+                        key: pitch.into(), 
+                        vel: 0.into() 
+                    },
+                }
+            });
         }
 
-        // End of Track
-        final_track.push(TrackEvent {
-            delta: 0.into(),
-            kind: TrackEventKind::Meta(MetaMessage::EndOfTrack),
-        });
-
-        smf.tracks.push(final_track);
+        // C. Convert to Delta Time
+        smf.tracks.push(to_delta_track(midi_events));
     }
 
     // 4. Serialize to Bytes
@@ -130,12 +171,81 @@ pub fn export(timeline: &Timeline) -> Result<Vec<u8>, Box<dyn std::error::Error>
     Ok(buffer)
 }
 
+/// The meter to write for a bar of `length` ticks: `written` when the bar
+/// is full, otherwise the smallest power-of-two subdivision that fits.
+fn bar_signature(written: TimeSignature, length: u64, ppq: u32) -> TimeSignature {
+    if length == written.ticks(ppq) || length == 0 { return written; }
+    let whole = 4 * ppq as u64;
+    let mut denominator = written.denominator.max(1);
+    while !(length * denominator).is_multiple_of(whole) && denominator < 64 {
+        denominator *= 2;
+    }
+    TimeSignature::new((length * denominator / whole).max(1), denominator)
+}
+
+/// Controller changes for `lane` shifted by `offset` ticks and stopped at
+/// `end`. Spec 21.2: ramps are sampled about every 10 ms at the tempo in
+/// force; repeated values are dropped.
+fn controller_events<'a>(lane: &Automation, offset: u64, end: u64, channel: u8, map: &TempoMap) -> Vec<TempEvent<'a>> {
+    let start = lane.start_tick + offset;
+    let end = end.max(start);
+    let mut events = Vec::new();
+    let mut last = None;
+    let mut tick = start;
+    loop {
+        let value = lane.value_at(tick - offset).round().clamp(0.0, 127.0) as u8;
+        if last != Some(value) {
+            last = Some(value);
+            events.push(TempEvent {
+                tick,
+                kind: TrackEventKind::Midi {
+                    channel: channel.into(),
+                    message: MidiMessage::Controller { controller: lane.controller.into(), value: value.into() },
+                },
+            });
+        }
+        if tick >= end { break; }
+        let step = (map.bpm_at(tick) / 60.0 * map.ppq as f64 * 0.010).round().max(1.0) as u64;
+        tick = (tick + step).min(end);
+    }
+    events
+}
+
 // Temporary struct for sorting before calculating Deltas
 struct TempEvent<'a> {
     tick: u64,
     kind: TrackEventKind<'a>,
 }
 
+/// Sorts events by absolute tick and converts them to delta times,
+/// terminated by End of Track.
+fn to_delta_track(mut events: Vec<TempEvent>) -> Vec<TrackEvent> {
+    events.sort_by_key(|e| e.tick);
+
+    let mut track = Vec::with_capacity(events.len() + 1);
+    let mut current_tick = 0;
+    for e in events {
+        let delta = e.tick - current_tick;
+
+        // midly uses u28 for deltas. Ensure we don't overflow (unlikely in music).
+        let delta_u28 = u28::from_int_lossy(delta as u32);
+
+        track.push(TrackEvent {
+            delta: delta_u28,
+            kind: e.kind,
+        });
+
+        current_tick = e.tick;
+    }
+
+    // End of Track
+    track.push(TrackEvent {
+        delta: 0.into(),
+        kind: TrackEventKind::Meta(MetaMessage::EndOfTrack),
+    });
+    track
+}
+
 // Helper to map string names to MIDI Program Numbers (0-127)
 fn parse_patch_name(name: &str) -> u8 {
     let n = name.to_lowercase();
@@ -146,6 +256,6 @@ fn parse_patch_name(name: &str) -> u8 {
     if n.contains("guitar") { return 24; }
     if n.contains("bass") { return 32; }
     if n.contains("flute") { return 73; }
-    if n.contains("drum") || n.contains("kit") { return 0; } // Drums use Channel 10, prog doesn't matter much
+    if n.contains("drum") || n.contains("kit") { return 0; } // Standard Kit on channel 10
     0 // Default
 }
\ No newline at end of file
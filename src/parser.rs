@@ -1,62 +1,185 @@
+// chumsky 0.9 combinators return `Simple<Token, Span>` errors by value.
+#![allow(clippy::result_large_err)]
+
 use chumsky::prelude::*;
-use crate::lexer::Token;
+use chumsky::Stream;
+use crate::diagnostic::Diagnostic;
+use crate::lexer::{self, Token};
+use crate::span::{FileId, Span};
+
+pub type ParseError = Simple<Token, Span>;
 
 // --- AST Structures ---
 
 #[derive(Debug, Clone)]
 pub struct Score {
-    pub header: Option<String>, 
+    pub header: Option<String>,
     pub items: Vec<TopLevel>,
+    pub span: Span,
 }
 
 #[derive(Debug, Clone)]
 pub enum TopLevel {
-    Meta(Vec<(String, Value)>),
-    Def { id: String, label: String, attributes: Vec<(String, Value)> },
-    Measure { id: Option<i64>, content: Vec<Statement> },
-    Import(String),
+    Meta(Vec<Property>, Span),
+    Def { id: String, label: String, attributes: Vec<Property>, span: Span },
+    Measure { id: Option<i64>, content: Vec<Statement>, span: Span },
+    Import(String, Span),
+    Var { name: String, value: Value, span: Span },
+    /// `macro Name(arg, arg=default) = { ... }`
+    Macro { name: String, params: Vec<(String, Option<Value>)>, body: Voice, span: Span },
+    If { condition: Condition, then: Vec<TopLevel>, otherwise: Vec<TopLevel>, span: Span },
 }
 
 #[derive(Debug, Clone)]
 pub enum Statement {
-    Assignment { staff_id: String, voices: Vec<Voice> },
-    LocalMeta(Vec<(String, Value)>),
+    /// `repeat_start` is a leading `|:`; `barline` the closing bar line, if written.
+    Assignment { staff_id: String, voices: Vec<Voice>, repeat_start: bool, barline: Option<BarLine>, span: Span },
+    LocalMeta(Vec<Property>, Span),
+    If { condition: Condition, then: Vec<Statement>, otherwise: Vec<Statement>, span: Span },
+}
+
+/// Spec 11.1: the bar line closing a measure.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BarLine {
+    /// `|`
+    #[default]
+    Single,
+    /// `||`
+    Double,
+    /// `|]`
+    Final,
+    /// `:|`
+    RepeatEnd,
+    /// `:|:` ends one repeat and starts the next.
+    RepeatDouble,
+}
+
+/// Spec 22.4: `if (target == "audio")`, `if (part_id != "vln")`, `if (debug)`
+#[derive(Debug, Clone, PartialEq)]
+pub enum Condition {
+    Compare(Value, CmpOp, Value, Span),
+    Truthy(Value, Span),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CmpOp {
+    Eq,
+    Ne,
 }
 
 #[derive(Debug, Clone)]
 pub struct Voice {
+    /// `v1`..`v4` inside a voice group; `None` for the plain staff stream.
+    pub id: Option<u8>,
     pub events: Vec<Event>,
+    pub span: Span,
 }
 
 #[derive(Debug, Clone)]
 pub enum Event {
-    Note { pitch: String, duration: Option<String>, attributes: Vec<Attribute> },
-    Chord { notes: Vec<String>, duration: Option<String>, attributes: Vec<Attribute> },
-    Rest { duration: Option<String> },
-    Tab { fret: u8, string: u8, duration: Option<String>, attributes: Vec<Attribute> },
-    Percussion { key: String, duration: Option<String>, attributes: Vec<Attribute> },
+    /// `tie` is set by a trailing `~` (Spec 6.6).
+    Note { pitch: String, duration: Option<Duration>, attributes: Vec<Attribute>, tie: bool, span: Span },
+    /// `ties` runs parallel to `notes`: `[c4~ e4 g4]` ties only the C.
+    Chord { notes: Vec<String>, ties: Vec<bool>, duration: Option<Duration>, attributes: Vec<Attribute>, span: Span },
+    Rest { duration: Option<Duration>, span: Span },
+    Tab { fret: u8, string: u8, duration: Option<Duration>, attributes: Vec<Attribute>, span: Span },
+    Percussion { key: String, duration: Option<Duration>, attributes: Vec<Attribute>, span: Span },
     // Recursive Voice for Tuplets
-    Tuplet { content: Voice, p: u64, q: u64 }, 
+    Tuplet { content: Voice, p: u64, q: u64, span: Span },
+    /// `$Name(args) + n`, or a parameter reference inside a macro body.
+    /// Replaced by the pre-processor.
+    MacroCall { name: String, args: Vec<Value>, transpose: i64, duration: Option<Duration>, attributes: Vec<Attribute>, span: Span },
+    /// Spec 10: `{ v1: ... | v2: ... }`
+    VoiceGroup { voices: Vec<Voice>, span: Span },
+    /// An expanded macro whose pitches are shifted by `semitones`.
+    Transposed { content: Voice, semitones: i64, span: Span },
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Value {
     Str(String),
     Num(i64),
     Float(f64),
     Id(String),
     Array(Vec<Value>),
+    /// `{ key: value, ... }` in source order (Spec 4.6).
+    Map(Vec<(String, Value)>),
+    /// `$Name`: substituted by the pre-processor.
+    Var(String, Span),
+}
+
+/// A meta `key: value` or def `key=value` pair. `span` covers the value,
+/// so range and type diagnostics can point at it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Property {
+    pub key: String,
+    pub value: Value,
+    pub span: Span,
+}
+
+/// A duration literal in source form: `:4.`, `:1*4` or `:grace`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Duration {
+    pub text: String,
+    pub span: Span,
 }
 
 #[derive(Debug, Clone)]
 pub struct Attribute {
     pub name: String,
     pub args: Vec<Value>,
+    pub span: Span,
+}
+
+impl TopLevel {
+    pub fn span(&self) -> Span {
+        match self {
+            TopLevel::Meta(_, span) | TopLevel::Import(_, span) => *span,
+            TopLevel::Def { span, .. } | TopLevel::Measure { span, .. }
+            | TopLevel::Var { span, .. } | TopLevel::Macro { span, .. } | TopLevel::If { span, .. } => *span,
+        }
+    }
+}
+
+impl Statement {
+    pub fn span(&self) -> Span {
+        match self {
+            Statement::Assignment { span, .. } | Statement::LocalMeta(_, span) | Statement::If { span, .. } => *span,
+        }
+    }
+}
+
+impl Event {
+    pub fn span(&self) -> Span {
+        match self {
+            Event::Note { span, .. } | Event::Chord { span, .. } | Event::Rest { span, .. }
+            | Event::Tab { span, .. } | Event::Percussion { span, .. } | Event::Tuplet { span, .. }
+            | Event::MacroCall { span, .. } | Event::Transposed { span, .. } | Event::VoiceGroup { span, .. } => *span,
+        }
+    }
+
+    /// The written duration literal, if the event has one.
+    pub fn duration(&self) -> Option<&Duration> {
+        match self {
+            Event::Note { duration, .. } | Event::Chord { duration, .. } | Event::Rest { duration, .. }
+            | Event::Tab { duration, .. } | Event::Percussion { duration, .. } | Event::MacroCall { duration, .. } => duration.as_ref(),
+            Event::Tuplet { .. } | Event::Transposed { .. } | Event::VoiceGroup { .. } => None,
+        }
+    }
 }
 
 // --- Parser Logic ---
 
-pub fn parser() -> impl Parser<Token, Score, Error = Simple<Token>> {
+/// Lexes and parses a complete source file. Spans are tagged with `file`.
+pub fn parse(source: &str, file: FileId) -> (Option<Score>, Vec<Diagnostic>) {
+    let (tokens, mut diagnostics) = lexer::tokenize(source, file);
+    let eoi = Span::new(file, source.len()..source.len() + 1);
+    let (score, errors) = parser().parse_recovery(Stream::from_iter(eoi, tokens.into_iter()));
+    diagnostics.extend(errors.into_iter().map(Diagnostic::from));
+    (score, diagnostics)
+}
+
+pub fn parser() -> impl Parser<Token, Score, Error = ParseError> {
     let identifier = select! { Token::Identifier(s) => s };
     let string_lit = select! { Token::StringLit(s) => s };
     let integer = select! { Token::Integer(i) => i };
@@ -65,108 +188,291 @@ pub fn parser() -> impl Parser<Token, Score, Error = Simple<Token>> {
     let duration = select! { Token::DurationLit(d) => d };
     let tab_lit = select! { Token::TabLit(t) => t };
 
-    let val_str = string_lit.clone().map(Value::Str);
-    let val_int = integer.clone().map(Value::Num);
-    let val_flt = float.clone().map(Value::Float);
-    let val_id  = identifier.clone().map(Value::Id);
-    let value = val_str.or(val_flt).or(val_int).or(val_id).boxed();
+    let val_str = string_lit.map(Value::Str);
+    // Spec 3.3: `time: 3/4` is shorthand for the string "3/4"
+    let val_frac = integer.then_ignore(just(Token::Slash)).then(integer)
+        .map(|(n, d)| Value::Str(format!("{}/{}", n, d)));
+    let val_int = integer.map(Value::Num);
+    // `pickup: :8` (kept in source form, like a quoted value)
+    let val_dur = duration.map(Value::Str);
+    let val_flt = float.map(Value::Float);
+    // `pan: -0.5`
+    let val_neg = just(Token::Minus)
+        .ignore_then(float.map(|f| Value::Float(-f)).or(integer.map(|i| Value::Num(-i))));
+    // Names such as `A` or `Bb` lex as pitches
+    let name = identifier.or(pitch);
+    let val_id  = name.map(Value::Id);
+    let val_var = just(Token::Dollar).ignore_then(name).map_with_span(Value::Var);
+    let value = recursive(|value| {
+        // Arrays: `tempo: [120, 90]`
+        let val_arr = just(Token::LBracket)
+            .ignore_then(value.clone().separated_by(just(Token::Comma)).allow_trailing())
+            .then_ignore(just(Token::RBracket))
+            .map(Value::Array);
+        // Maps: `keyswitch={ arco: 24, pizz: 25 }`
+        let val_map = just(Token::LBrace)
+            .ignore_then(name.then_ignore(just(Token::Colon)).then(value).separated_by(just(Token::Comma)).allow_trailing())
+            .then_ignore(just(Token::RBrace))
+            .map(Value::Map);
+        val_str.or(val_flt).or(val_frac).or(val_int).or(val_neg).or(val_dur).or(val_id).or(val_var).or(val_arr).or(val_map)
+    }).boxed();
+    let args = just(Token::LParen)
+        .ignore_then(value.clone().separated_by(just(Token::Comma)))
+        .then_ignore(just(Token::RParen));
 
+    // `.f` lexes as a pitch, so attribute names accept both
     let attribute = just(Token::Dot)
-        .ignore_then(identifier.clone())
+        .ignore_then(identifier.or(pitch))
         .then(just(Token::LParen).ignore_then(value.clone().separated_by(just(Token::Comma))).then_ignore(just(Token::RParen)).or_not())
-        .map(|(name, args)| Attribute { name, args: args.unwrap_or_default() })
+        .map_with_span(|(name, args), span| Attribute { name, args: args.unwrap_or_default(), span })
         .boxed();
 
-    // Recursive Event Parser for Tuplets
+    // `vlc:` opens the next statement; it is never a percussion hit
+    let not_label = just(Token::Colon).not().rewind();
+
+    let voice_id = identifier.try_map(|s, span| {
+        s.strip_prefix('v')
+            .and_then(|n| n.parse::<u8>().ok())
+            .filter(|n| (1..=4).contains(n))
+            .ok_or_else(|| Simple::custom(span, format!("Unknown voice identifier `{}` (expected v1 to v4)", s)))
+    });
+
+    // Spec 5.1.2: `:1 * 4`, kept in source form as ":1*4"
+    let event_duration = duration.then(just(Token::Star).ignore_then(integer).or_not())
+        .map_with_span(|(d, count), span| Duration {
+            text: match count {
+                Some(n) => format!("{}*{}", d, n),
+                None => d,
+            },
+            span,
+        });
+
+    // `:3/2` lexes as a fractional duration
+    let tuplet_ratio = duration.try_map(|d, span| {
+        d[1..].split_once('/')
+            .and_then(|(p, q)| Some((p.parse::<i64>().ok()?, q.parse::<i64>().ok()?)))
+            .filter(|&(p, q)| p > 0 && q > 0)
+            .ok_or_else(|| Simple::custom(span, format!("Expected a tuplet ratio such as `:3/2`, found `{}`", d)))
+    }).or(just(Token::Colon).ignore_then(integer).then_ignore(just(Token::Slash)).then(integer));
+
+    // Recursive Event Parser for Tuplets and Voice Groups
     let event = recursive(|event| {
-        let note_event = pitch.then(duration.clone().or_not()).then(attribute.clone().repeated())
-            .map(|((p, d), attrs)| Event::Note { pitch: p, duration: d, attributes: attrs });
+        // Ties: `c4~:4`, `c4:4~` or `c4:4.stacc~`
+        let tie = just(Token::Tilde).or_not().map(|t| t.is_some());
+        let note_event = pitch.then(tie.clone()).then(event_duration.clone().or_not()).then(tie.clone())
+            .then(attribute.clone().repeated()).then(tie.clone())
+            .then_ignore(not_label.clone())
+            .map_with_span(|(((((p, t1), d), t2), attrs), t3), span| Event::Note {
+                pitch: p, duration: d, attributes: attrs, tie: t1 || t2 || t3, span,
+            });
 
-        // Chord: [ c4 e4 g4 ]
+        // Chord: [ c4 e4 g4 ], or simultaneous drum keys: [ k c ]
+        // A tie after the bracket ties every note.
         let chord_event = just(Token::LBracket)
-            .ignore_then(pitch.repeated())
+            .ignore_then(name.then(tie.clone()).repeated())
             .then_ignore(just(Token::RBracket))
-            .then(duration.clone().or_not())
-            .then(attribute.clone().repeated())
-            .map(|((notes, d), attrs)| Event::Chord { notes, duration: d, attributes: attrs });
+            .then(event_duration.clone().or_not()).then(tie.clone())
+            .then(attribute.clone().repeated()).then(tie.clone())
+            .map_with_span(|((((members, d), t1), attrs), t2), span| {
+                let (notes, ties): (Vec<String>, Vec<bool>) = members.into_iter()
+                    .map(|(note, tie)| (note, tie || t1 || t2))
+                    .unzip();
+                Event::Chord { notes, ties, duration: d, attributes: attrs, span }
+            });
 
         let rest_event = select! { Token::Identifier(s) if s == "r" => s }
-            .ignore_then(duration.clone().or_not())
-            .map(|d| Event::Rest { duration: d });
-            
-        let tab_event = tab_lit.then(duration.clone().or_not()).then(attribute.clone().repeated())
-            .map(|((t, d), attrs)| {
+            .ignore_then(event_duration.clone().or_not())
+            .then_ignore(not_label.clone())
+            .map_with_span(|d, span| Event::Rest { duration: d, span });
+
+        let tab_event = tab_lit.then(event_duration.clone().or_not()).then(attribute.clone().repeated())
+            .map_with_span(|((t, d), attrs), span| {
                 let parts: Vec<&str> = t.split('-').collect();
-                Event::Tab { fret: parts[0].parse().unwrap_or(0), string: parts[1].parse().unwrap_or(1), duration: d, attributes: attrs }
+                Event::Tab { fret: parts[0].parse().unwrap_or(0), string: parts[1].parse().unwrap_or(1), duration: d, attributes: attrs, span }
             });
 
         let perc_event = select! { Token::Identifier(s) if s != "r" => s }
-            .then(duration.clone().or_not()).then(attribute.clone().repeated())
-            .map(|((k, d), attrs)| Event::Percussion { key: k, duration: d, attributes: attrs });
+            .then(event_duration.clone().or_not()).then(attribute.clone().repeated())
+            .then_ignore(not_label.clone())
+            .map_with_span(|((k, d), attrs), span| Event::Percussion { key: k, duration: d, attributes: attrs, span });
+
+        // Macro call: $RockBeat(h_open, 110), $Lick + 5, $hat.vel($v)
+        let transpose = just(Token::Plus).to(1).or(just(Token::Minus).to(-1))
+            .then(integer).map(|(sign, n)| sign * n);
+        let macro_call = just(Token::Dollar).ignore_then(name)
+            .then(args.clone().or_not())
+            .then(transpose.or_not())
+            .then(event_duration.clone().or_not())
+            .then(attribute.clone().repeated())
+            .map_with_span(|((((name, args), transpose), d), attrs), span| Event::MacroCall {
+                name,
+                args: args.unwrap_or_default(),
+                transpose: transpose.unwrap_or(0),
+                duration: d,
+                attributes: attrs,
+                span,
+            });
 
         // Tuplet: ( c d e ):3/2
         let tuplet_event = just(Token::LParen)
-            .ignore_then(event.repeated().map(|events| Voice { events }))
+            .ignore_then(event.clone().repeated().map_with_span(|events, span| Voice { id: None, events, span }))
             .then_ignore(just(Token::RParen))
-            .then_ignore(just(Token::Colon))
-            .then(integer.clone())
-            .then_ignore(just(Token::Slash))
-            .then(integer.clone())
-            .map(|((content, p), q)| Event::Tuplet { content, p: p as u64, q: q as u64 });
+            .then(tuplet_ratio.clone())
+            .map_with_span(|(content, (p, q)), span| Event::Tuplet { content, p: p as u64, q: q as u64, span });
+
+        // Voice Group: { v1: c5:2 d5:2 | v2: a4:1 | }
+        let group_voice = voice_id.then_ignore(just(Token::Colon))
+            .then(event.repeated().separated_by(just(Token::Pipe)).allow_trailing())
+            .map_with_span(|(id, segments), span| Voice {
+                id: Some(id),
+                events: segments.into_iter().flatten().collect(),
+                span,
+            });
+        let voice_group = just(Token::LBrace)
+            .ignore_then(group_voice.repeated().at_least(1))
+            .then_ignore(just(Token::RBrace))
+            .map_with_span(|voices, span| Event::VoiceGroup { voices, span });
 
         choice((
+            voice_group,
             tuplet_event, // Try recursive structure first
+            macro_call,
             rest_event,
             chord_event,  // Then chords
-            note_event, 
+            note_event,
             tab_event,
             perc_event
         ))
     });
 
-    let voice = event.repeated().map(|events| Voice { events });
-    
-    let voice_group = voice.separated_by(just(Token::Pipe)).allow_trailing().map(|voices| voices);
+    let voice = event.repeated().map_with_span(|events, span| Voice { id: None, events, span }).boxed();
+
+    let voice_group = voice.clone().separated_by(just(Token::Pipe));
+    let barline = select! {
+        Token::DoubleBar => BarLine::Double,
+        Token::FinalBar => BarLine::Final,
+        Token::RepeatEnd => BarLine::RepeatEnd,
+        Token::RepeatDouble => BarLine::RepeatDouble,
+    };
 
-    let assignment = identifier.clone()
+    // Staff ids such as `a` or `bb` lex as pitches
+    let assignment = name
         .then_ignore(just(Token::Colon))
+        .then(just(Token::RepeatStart).or_not().map(|t| t.is_some()))
         .then(voice_group)
-        .then_ignore(just(Token::Pipe).or_not())
-        .map(|(id, voices)| Statement::Assignment { staff_id: id, voices });
+        .then(barline.or_not())
+        .map_with_span(|(((id, repeat_start), mut voices), mut barline), span| {
+            // A trailing `|` leaves an empty segment behind
+            if voices.len() > 1 && voices.last().is_some_and(|v| v.events.is_empty()) {
+                voices.pop();
+                barline = barline.or(Some(BarLine::Single));
+            }
+            Statement::Assignment { staff_id: id, voices, repeat_start, barline, span }
+        });
 
-    let key_value = identifier.clone().then_ignore(just(Token::Colon)).then(value.clone());
+    // Spec 14.1: `vln.vol: 0.5` addresses a staff's mixer
+    let meta_key = name.then(just(Token::Dot).ignore_then(identifier).or_not())
+        .map(|(key, field)| match field {
+            Some(field) => format!("{}.{}", key, field),
+            None => key,
+        });
+    let property_value = value.clone().map_with_span(|value, span| (value, span));
+    let key_value = meta_key.then_ignore(just(Token::Colon)).then(property_value.clone())
+        .map(|(key, (value, span))| Property { key, value, span });
     let meta_block = just(Token::KwMeta).ignore_then(just(Token::LBrace))
         .ignore_then(key_value.separated_by(just(Token::Comma)))
         .then_ignore(just(Token::RBrace));
 
-    let statement = choice((
-        assignment,
-        meta_block.clone().map(Statement::LocalMeta)
-    ));
+    let operand = value.clone();
+    let cmp_op = just(Token::EqEq).to(CmpOp::Eq).or(just(Token::NotEq).to(CmpOp::Ne));
+    let condition = just(Token::LParen)
+        .ignore_then(operand.clone().then(cmp_op.then(operand).or_not()))
+        .then_ignore(just(Token::RParen))
+        .map_with_span(|(lhs, rhs), span| match rhs {
+            Some((op, rhs)) => Condition::Compare(lhs, op, rhs, span),
+            None => Condition::Truthy(lhs, span),
+        })
+        .boxed();
+
+    let statement = recursive(|statement| {
+        let block = just(Token::LBrace).ignore_then(statement.repeated()).then_ignore(just(Token::RBrace));
+        let if_stmt = recursive(|if_stmt| {
+            just(Token::KwIf).ignore_then(condition.clone()).then(block.clone())
+                .then(just(Token::KwElse).ignore_then(block.clone().or(if_stmt.map(|s| vec![s]))).or_not())
+                .map_with_span(|((condition, then), otherwise), span| Statement::If {
+                    condition, then, otherwise: otherwise.unwrap_or_default(), span,
+                })
+        });
 
-    let def_attr = identifier.clone().then_ignore(just(Token::Equals)).then(value.clone());
-    
-    // CRITICAL FIX: Added explicit type annotations to map closure
-    let def_block = just(Token::KwDef).ignore_then(identifier.clone())
-        .then(string_lit.clone().or_not())
+        choice((
+            assignment,
+            meta_block.clone().map_with_span(Statement::LocalMeta),
+            if_stmt,
+        ))
+    });
+
+    let def_attr = identifier.then_ignore(just(Token::Equals)).then(property_value)
+        .map(|(key, (value, span))| Property { key, value, span });
+
+    let def_block = just(Token::KwDef).ignore_then(name)
+        .then(string_lit.or_not())
         .then(def_attr.repeated())
-        .map(|((id, label), attrs): ((String, Option<String>), Vec<(String, Value)>)| TopLevel::Def { 
-            id, 
-            label: label.unwrap_or_default(), 
-            attributes: attrs 
+        .map_with_span(|((id, label), attrs), span| TopLevel::Def {
+            id,
+            label: label.unwrap_or_default(),
+            attributes: attrs,
+            span,
         });
 
-    let measure_block = just(Token::KwMeasure).ignore_then(integer.clone().or_not())
+    let measure_block = just(Token::KwMeasure).ignore_then(integer.or_not())
         .then_ignore(just(Token::LBrace)).then(statement.repeated()).then_ignore(just(Token::RBrace))
-        .map(|(num, content)| TopLevel::Measure { id: num, content });
-
-    let root_content = choice((
-        meta_block.map(TopLevel::Meta),
-        def_block,
-        measure_block
-    )).repeated();
-
-    just(Token::KwTenuto)
-        .ignore_then(just(Token::LBrace)).ignore_then(root_content).then_ignore(just(Token::RBrace))
-        .map(|items| Score { header: Some("2.0".into()), items })
-}
\ No newline at end of file
+        .map_with_span(|(num, content), span| TopLevel::Measure { id: num, content, span });
+
+    let var_stmt = just(Token::KwVar).ignore_then(name)
+        .then_ignore(just(Token::Equals)).then(value.clone())
+        .map_with_span(|(name, value), span| TopLevel::Var { name, value, span });
+
+    let param = name.then(just(Token::Equals).ignore_then(value.clone()).or_not());
+    let macro_def = just(Token::KwMacro).ignore_then(name)
+        .then(just(Token::LParen).ignore_then(param.separated_by(just(Token::Comma))).then_ignore(just(Token::RParen)).or_not())
+        .then_ignore(just(Token::Equals))
+        .then_ignore(just(Token::LBrace)).then(voice).then_ignore(just(Token::RBrace))
+        .map_with_span(|((name, params), body), span| TopLevel::Macro { name, params: params.unwrap_or_default(), body, span });
+
+    let import_stmt = just(Token::KwImport).ignore_then(string_lit)
+        .map_with_span(TopLevel::Import);
+
+    let root_item = recursive(|root_item| {
+        let block = just(Token::LBrace).ignore_then(root_item.repeated()).then_ignore(just(Token::RBrace));
+        let if_block = recursive(|if_block| {
+            just(Token::KwIf).ignore_then(condition.clone()).then(block.clone())
+                .then(just(Token::KwElse).ignore_then(block.clone().or(if_block.map(|i| vec![i]))).or_not())
+                .map_with_span(|((condition, then), otherwise), span| TopLevel::If {
+                    condition, then, otherwise: otherwise.unwrap_or_default(), span,
+                })
+        });
+
+        choice((
+            meta_block.map_with_span(TopLevel::Meta),
+            import_stmt,
+            var_stmt,
+            macro_def,
+            def_block,
+            measure_block,
+            if_block,
+        ))
+    });
+    let root_content = root_item.repeated();
+
+    let wrapped = just(Token::KwTenuto)
+        .ignore_then(just(Token::LBrace)).ignore_then(root_content.clone()).then_ignore(just(Token::RBrace))
+        .map_with_span(|items, span| Score { header: Some("2.0".into()), items, span })
+        .then_ignore(end());
+
+    // Spec 3.1 Implicit Root: imported fragments may omit the `tenuto` wrapper
+    let implicit = root_content
+        .map_with_span(|items, span| Score { header: None, items, span })
+        .then_ignore(end());
+
+    wrapped.or(implicit)
+}
@@ -0,0 +1,291 @@
+//! Spec 11: unrolls the written timeline into playback order, following
+//! repeats, volta brackets and D.C./D.S. jumps. Spec 14.4-14.5: swing and
+//! humanization are applied to the performed copy only.
+
+use crate::ir::{AtomicEvent, Automation, EventKind, MeasureInfo, Marker, TempoMap, Timeline, Track};
+use crate::parser::BarLine;
+use std::collections::{HashMap, HashSet};
+
+/// Written measure numbers in the order they are played.
+pub fn order(timeline: &Timeline) -> Vec<i64> {
+    let bars: Vec<(i64, &MeasureInfo)> = timeline.measures.iter().map(|(&i, m)| (i, m)).collect();
+    let mut order = Vec::new();
+
+    let mut i = 0;
+    // Target of the next `:|` and the pass through it (1-based)
+    let mut section_start = 0;
+    let mut pass = 1;
+    let mut in_volta = false;
+    // The D.C./D.S. being followed; repeats are not taken after a jump
+    let mut jump: Option<Marker> = None;
+    let mut taken = HashSet::new();
+
+    while let Some(&(index, bar)) = bars.get(i) {
+        if bar.repeat_start && i != section_start {
+            section_start = i;
+            pass = 1;
+        }
+        if bar.volta.is_empty() {
+            if in_volta {
+                in_volta = false;
+                section_start = i;
+                pass = 1;
+            }
+        } else {
+            in_volta = true;
+            // After a jump only the last ending is played
+            let playing = if jump.is_some() { passes(&bars, i) } else { pass };
+            if !bar.volta.contains(&playing) {
+                i += 1;
+                continue;
+            }
+        }
+
+        order.push(index);
+
+        match jump {
+            Some(Marker::DcAlFine | Marker::DsAlFine) if bar.markers.contains(&Marker::Fine) => break,
+            Some(Marker::DcAlCoda | Marker::DsAlCoda) if bar.markers.contains(&Marker::ToCoda) => {
+                if let Some(coda) = bars.iter().skip(i + 1).position(|(_, m)| m.markers.contains(&Marker::Coda)) {
+                    i += coda + 1;
+                    continue;
+                }
+            }
+            _ => {}
+        }
+
+        if jump.is_none() && matches!(bar.barline, BarLine::RepeatEnd | BarLine::RepeatDouble) {
+            if pass < passes(&bars, i) {
+                pass += 1;
+                i = section_start;
+                in_volta = false;
+                continue;
+            }
+            section_start = i + 1;
+            if bars.get(i + 1).is_none_or(|(_, next)| next.volta.is_empty()) { pass = 1; }
+        }
+
+        let dc_ds = bar.markers.iter().copied()
+            .find(|m| matches!(m, Marker::DcAlFine | Marker::DsAlFine | Marker::DcAlCoda | Marker::DsAlCoda));
+        if let Some(marker) = dc_ds {
+            let target = match marker {
+                Marker::DcAlFine | Marker::DcAlCoda => Some(0),
+                _ => bars.iter().position(|(_, m)| m.markers.contains(&Marker::Segno)),
+            };
+            if let Some(target) = target.filter(|_| taken.insert(i)) {
+                jump = Some(marker);
+                i = target;
+                in_volta = false;
+                continue;
+            }
+        }
+        i += 1;
+    }
+    order
+}
+
+/// Passes through the repeat ending at bar `i`: the highest volta number in
+/// the brackets from `i` onwards, or 2 for a plain repeat.
+fn passes(bars: &[(i64, &MeasureInfo)], i: usize) -> u32 {
+    bars[i..].iter()
+        .take_while(|(_, m)| !m.volta.is_empty())
+        .flat_map(|(_, m)| m.volta.iter().copied())
+        .max()
+        .unwrap_or(2)
+}
+
+/// The playback timeline: written bars laid end to end in `order`, with
+/// fermata holds spliced in. Events, measures and tempo changes are copied
+/// with their ticks shifted.
+pub fn unroll(timeline: &Timeline) -> Timeline {
+    let written = &timeline.tempo_map;
+    // Ticks held by fermatas in the written span `after..=until`
+    let held = |after: u64, until: u64| -> u64 {
+        if until <= after { return 0; }
+        timeline.holds.range(after + 1..=until).map(|(_, hold)| hold).sum()
+    };
+    let mut unrolled = Timeline {
+        title: timeline.title.clone(),
+        tempo: timeline.tempo,
+        tempo_map: TempoMap::new(written.bpm_at(0), written.ppq),
+        tracks: timeline.tracks.iter()
+            .map(|(id, track)| (id.clone(), Track { automation: Vec::new(), events: Vec::new(), ..track.clone() }))
+            .collect(),
+        measures: Default::default(),
+        holds: Default::default(),
+        feel: timeline.feel.clone(),
+        warnings: Vec::new(),
+    };
+
+    // Consecutive written bars are copied as one run so ramps stay whole
+    let mut runs: Vec<(u64, u64)> = Vec::new();
+    let first = timeline.measures.keys().next().copied().unwrap_or(1);
+    let mut playback_tick = 0;
+    let mut previous: Option<i64> = None;
+    for (number, index) in (first..).zip(order(timeline)) {
+        let bar = &timeline.measures[&index];
+        match runs.last_mut() {
+            Some((_, end)) if previous == Some(index - 1) && *end == bar.start_tick => *end += bar.length_ticks,
+            _ => runs.push((bar.start_tick, bar.start_tick + bar.length_ticks)),
+        }
+        previous = Some(index);
+
+        let length_ticks = bar.length_ticks + held(bar.start_tick, bar.start_tick + bar.length_ticks);
+        unrolled.measures.insert(number, MeasureInfo {
+            start_tick: playback_tick,
+            length_ticks,
+            time_signature: bar.time_signature,
+            ..Default::default()
+        });
+        playback_tick += length_ticks;
+    }
+
+    let mut run_start = 0;
+    for (start, end) in runs {
+        let shift = |tick: u64| tick - start + run_start + held(start, tick);
+
+        for (id, track) in &timeline.tracks {
+            let events = &track.events;
+            let from = events.partition_point(|e| e.tick < start);
+            let to = events.partition_point(|e| e.tick < end);
+            let target = unrolled.tracks.get_mut(id).unwrap();
+
+            // Like the tempo: the value in force at the run's start, then each change in it
+            for (i, lane) in track.automation.iter().enumerate() {
+                let next = track.automation[i + 1..].iter()
+                    .find(|n| n.controller == lane.controller)
+                    .map_or(u64::MAX, |n| n.start_tick);
+                if lane.start_tick >= end || next <= start { continue; }
+                let from = lane.start_tick.max(start);
+                let until = lane.end_tick.min(end).min(next).max(from);
+                target.automation.push(Automation {
+                    start_tick: shift(from),
+                    end_tick: shift(until),
+                    from: lane.value_at(from),
+                    to: lane.value_at(until),
+                    ..*lane
+                });
+            }
+
+            target.events.extend(events[from..to].iter().map(|e| {
+                // Notes sounding into a fermata are held through it
+                let sounding = e.sounding_ticks + held(e.tick, (e.tick + e.duration_ticks.max(e.sounding_ticks)).min(end));
+                AtomicEvent { tick: shift(e.tick), sounding_ticks: sounding, ..e.clone() }
+            }));
+        }
+
+        // The tempo in force when the run begins, then every change inside it
+        unrolled.tempo_map.set(run_start, written.bpm_at(start));
+        for (i, seg) in written.segments.iter().enumerate() {
+            // A ramp stops at the run's end or where the next change takes over
+            let next = written.segments.get(i + 1).map_or(u64::MAX, |n| n.start_tick);
+            let from = seg.start_tick.max(start);
+            let until = seg.end_tick.min(end).min(next).max(from);
+            if seg.start_tick >= end || next <= from || (seg.start_tick < start && until == from) { continue; }
+            unrolled.tempo_map.ramp(shift(from), shift(until), written.bpm_at(from), written.bpm_at(until), seg.curve);
+        }
+        run_start += end - start + held(start, end);
+    }
+    unrolled
+}
+
+/// The timeline as performed: swung on the written grid, unrolled, then
+/// humanized. `timeline` itself is left as notated.
+pub fn render(timeline: &Timeline) -> Timeline {
+    let mut swung = timeline.clone();
+    swing(&mut swung);
+    let mut performed = unroll(&swung);
+    humanize(&mut performed);
+    performed
+}
+
+/// Spec 14.4: delays off-beat subdivisions by displacing onsets and ends
+/// through `offset_ticks` and `sounding_ticks`. Positions are measured
+/// from the bar line, so a pickup keeps the beats of a full bar.
+pub fn swing(timeline: &mut Timeline) {
+    let Some(percent) = timeline.feel.swing else { return };
+    if percent == 50.0 { return; }
+    let ratio = percent / 100.0;
+    let ppq = timeline.tempo_map.ppq as u64;
+
+    // Where each measure's beats are counted from, by start tick
+    let origins: Vec<(u64, i64)> = timeline.measures.values()
+        .map(|m| (m.start_tick, (m.start_tick + m.length_ticks) as i64 - m.time_signature.ticks(ppq as u32) as i64))
+        .collect();
+
+    for track in timeline.tracks.values_mut() {
+        for event in &mut track.events {
+            let at = origins.partition_point(|&(start, _)| start <= event.tick);
+            let origin = at.checked_sub(1).map_or(0, |i| origins[i].1);
+            // Spec 14.4: 16ths swing at slow tempos, 8ths otherwise
+            let grid = timeline.feel.swing_grid.unwrap_or_else(|| {
+                if timeline.tempo_map.bpm_at(event.tick) < 60.0 { ppq / 4 } else { ppq / 2 }
+            });
+
+            let start = event.tick as i64 - origin;
+            let end = start + event.sounding_ticks as i64;
+            let (swung_start, swung_end) = (warp(start, grid, ratio), warp(end, grid, ratio));
+            event.offset_ticks += swung_start - start;
+            event.sounding_ticks = (swung_end - swung_start).max(1) as u64;
+        }
+    }
+}
+
+/// Maps a straight position onto the swung grid: the first half of each
+/// pair of `grid` subdivisions is stretched to `ratio` of the pair.
+fn warp(position: i64, grid: u64, ratio: f64) -> i64 {
+    let pair = 2 * grid as i64;
+    let (base, p) = (position.div_euclid(pair) * pair, position.rem_euclid(pair) as f64);
+    let (grid, split) = (grid as f64, pair as f64 * ratio);
+    let swung = if p <= grid { p * split / grid } else { split + (p - grid) * (pair as f64 - split) / grid };
+    base + swung.round() as i64
+}
+
+/// Spec 14.5: random onset and velocity offsets of up to `humanize` of a
+/// 16th note and of the velocity. The same seed renders the same jitter.
+pub fn humanize(timeline: &mut Timeline) {
+    let amount = timeline.feel.humanize;
+    if amount <= 0.0 { return; }
+    let sixteenth = timeline.tempo_map.ppq as f64 / 4.0;
+    let mut rng = Rng(timeline.feel.seed);
+
+    // Tracks in a fixed order so the sequence does not depend on hashing
+    let mut ids: Vec<&String> = timeline.tracks.keys().collect();
+    ids.sort();
+    let ids: Vec<String> = ids.into_iter().cloned().collect();
+    for id in ids {
+        let events = &mut timeline.tracks.get_mut(&id).unwrap().events;
+        // Earliest jitter of the notes at each tick
+        let mut shifts: HashMap<u64, i64> = HashMap::new();
+        for event in events.iter_mut() {
+            let EventKind::Note { velocity, .. } = &mut event.kind else { continue };
+            let shift = (rng.signed_unit() * amount * sixteenth).round() as i64;
+            event.offset_ticks += shift;
+            let jittered = *velocity as f64 * (1.0 + rng.signed_unit() * amount);
+            *velocity = jittered.round().clamp(1.0, 127.0) as u8;
+            shifts.entry(event.tick).and_modify(|s| *s = (*s).min(shift)).or_insert(shift);
+        }
+        // Keyswitches move with the notes they select so they keep their lead
+        for event in events.iter_mut().filter(|e| matches!(e.kind, EventKind::KeySwitch { .. })) {
+            event.offset_ticks += shifts.get(&event.tick).copied().unwrap_or(0);
+        }
+    }
+}
+
+/// SplitMix64: tiny, seedable and identical on every platform.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in `[-1, 1)`.
+    fn signed_unit(&mut self) -> f64 {
+        (self.next() >> 11) as f64 / (1u64 << 53) as f64 * 2.0 - 1.0
+    }
+}
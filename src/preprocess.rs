@@ -0,0 +1,373 @@
+use crate::diagnostic::Diagnostic;
+use crate::lexer::{self, Token};
+use crate::parser::{Attribute, CmpOp, Condition, Duration, Event, Property, Score, Statement, TopLevel, Value, Voice};
+use crate::span::Span;
+use std::collections::HashMap;
+
+/// Spec 15.4: Maximum macro expansion depth.
+pub const MAX_MACRO_DEPTH: usize = 64;
+
+/// Spec 22.4: The environment `if` conditions are evaluated against.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BuildTarget {
+    /// `"score"`, `"audio"` or `"part"`.
+    pub target: String,
+    /// The staff being rendered when `target` is `"part"`.
+    pub part_id: Option<String>,
+    pub debug: bool,
+}
+
+impl Default for BuildTarget {
+    fn default() -> Self {
+        // This compiler renders MIDI
+        Self { target: "audio".into(), part_id: None, debug: false }
+    }
+}
+
+/// Spec 15: The Pre-Processor. Resolves `if` blocks against `target`,
+/// substitutes `$Name` references and expands macro invocations before
+/// linearization. `var` and `macro` declarations are consumed and do not
+/// reach the IR.
+pub fn expand(mut score: Score, target: &BuildTarget) -> (Score, Vec<Diagnostic>) {
+    let mut pre = Preprocessor { target: target.clone(), ..Default::default() };
+    let mut items = Vec::with_capacity(score.items.len());
+    pre.expand_items(std::mem::take(&mut score.items), &mut items);
+    score.items = items;
+    (score, pre.diagnostics)
+}
+
+/// Spec 22.4: decides top-level `if` blocks for the loader, so imports in a
+/// branch that is not taken are never read. Conditions may test variables,
+/// so the declarations seen so far are tracked; any diagnostics about them
+/// are left to `expand`.
+pub(crate) struct Conditions(Preprocessor);
+
+impl Conditions {
+    pub(crate) fn new(target: &BuildTarget) -> Self {
+        Self(Preprocessor { target: target.clone(), ..Default::default() })
+    }
+
+    pub(crate) fn declare(&mut self, name: &str, value: &Value, span: Span) {
+        let mut value = value.clone();
+        self.0.resolve(&mut value, &Bindings::new());
+        self.0.diagnostics.clear();
+        self.0.vars.entry(name.to_string()).or_insert((value, span));
+    }
+
+    pub(crate) fn evaluate(&mut self, condition: &Condition, diagnostics: &mut Vec<Diagnostic>) -> bool {
+        let taken = self.0.evaluate(condition);
+        diagnostics.append(&mut self.0.diagnostics);
+        taken
+    }
+}
+
+/// Macro arguments bound to parameter names during one expansion.
+type Bindings = HashMap<String, Value>;
+
+#[derive(Clone)]
+struct MacroDef {
+    params: Vec<(String, Option<Value>)>,
+    body: Voice,
+    span: Span,
+}
+
+#[derive(Default)]
+struct Preprocessor {
+    target: BuildTarget,
+    /// Global scope (Spec 15.1). Imported files have already been spliced
+    /// in place, so their variables are visible to everything after them.
+    vars: HashMap<String, (Value, Span)>,
+    macros: HashMap<String, MacroDef>,
+    /// Macros currently being expanded, outermost first.
+    stack: Vec<String>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Preprocessor {
+    fn expand_items(&mut self, items: Vec<TopLevel>, out: &mut Vec<TopLevel>) {
+        let globals = Bindings::new();
+        for mut item in items {
+            match &mut item {
+                TopLevel::Var { name, value, span } => {
+                    self.resolve(value, &globals);
+                    self.declare(name, value.clone(), *span);
+                    continue;
+                }
+                TopLevel::Macro { name, params, body, span } => {
+                    for default in params.iter_mut().filter_map(|(_, d)| d.as_mut()) {
+                        self.resolve(default, &globals);
+                    }
+                    self.define(name, params.clone(), body.clone(), *span);
+                    continue;
+                }
+                TopLevel::If { condition, then, otherwise, .. } => {
+                    let branch = if self.evaluate(condition) { then } else { otherwise };
+                    self.expand_items(std::mem::take(branch), out);
+                    continue;
+                }
+                TopLevel::Meta(kvs, _) => self.resolve_pairs(kvs),
+                TopLevel::Def { attributes, .. } => self.resolve_pairs(attributes),
+                TopLevel::Measure { content, .. } => {
+                    let mut statements = Vec::with_capacity(content.len());
+                    self.expand_statements(std::mem::take(content), &mut statements);
+                    *content = statements;
+                }
+                TopLevel::Import(..) => {}
+            }
+            out.push(item);
+        }
+    }
+
+    fn expand_statements(&mut self, statements: Vec<Statement>, out: &mut Vec<Statement>) {
+        for mut stmt in statements {
+            match &mut stmt {
+                Statement::LocalMeta(kvs, _) => self.resolve_pairs(kvs),
+                Statement::Assignment { voices, .. } => {
+                    for voice in voices {
+                        let events = std::mem::take(&mut voice.events);
+                        voice.events = self.expand_events(events, &Bindings::new());
+                    }
+                }
+                Statement::If { condition, then, otherwise, .. } => {
+                    let branch = if self.evaluate(condition) { then } else { otherwise };
+                    self.expand_statements(std::mem::take(branch), out);
+                    continue;
+                }
+            }
+            out.push(stmt);
+        }
+    }
+
+    fn evaluate(&mut self, condition: &Condition) -> bool {
+        match condition {
+            Condition::Compare(lhs, op, rhs, span) => {
+                let equal = self.operand(lhs, *span) == self.operand(rhs, *span);
+                (*op == CmpOp::Eq) == equal
+            }
+            Condition::Truthy(value, span) => {
+                let v = self.operand(value, *span);
+                !(v.is_empty() || v == "false" || v == "0")
+            }
+        }
+    }
+
+    /// Bare names refer to the build environment; literals compare as text.
+    fn operand(&mut self, value: &Value, span: Span) -> String {
+        let mut value = value.clone();
+        self.resolve(&mut value, &Bindings::new());
+        match value {
+            Value::Id(name) => match name.as_str() {
+                "target" => self.target.target.clone(),
+                "part_id" => self.target.part_id.clone().unwrap_or_default(),
+                "debug" => self.target.debug.to_string(),
+                "true" | "false" => name,
+                _ => {
+                    self.diagnostics.push(
+                        Diagnostic::error("E2001", format!("Undefined identifier `{}` in condition", name), span)
+                            .with_help("Conditions may inspect `target`, `part_id`, `debug` and `$variables`")
+                    );
+                    String::new()
+                }
+            },
+            Value::Str(s) => s,
+            Value::Num(n) => n.to_string(),
+            Value::Float(f) => f.to_string(),
+            _ => String::new(),
+        }
+    }
+
+    fn declare(&mut self, name: &str, value: Value, span: Span) {
+        if let Some((_, first)) = self.vars.get(name) {
+            self.diagnostics.push(
+                Diagnostic::error("E2002", format!("Duplicate definition of variable `${}`", name), span)
+                    .with_label(*first, "first defined here")
+                    .with_help("Variables are immutable; choose a unique name")
+            );
+            return;
+        }
+        self.vars.insert(name.to_string(), (value, span));
+    }
+
+    fn define(&mut self, name: &str, params: Vec<(String, Option<Value>)>, body: Voice, span: Span) {
+        if let Some(first) = self.macros.get(name) {
+            self.diagnostics.push(
+                Diagnostic::error("E2002", format!("Duplicate definition of macro `${}`", name), span)
+                    .with_label(first.span, "first defined here")
+            );
+            return;
+        }
+        self.macros.insert(name.to_string(), MacroDef { params, body, span });
+    }
+
+    /// Macro arguments shadow global variables.
+    fn resolve(&mut self, value: &mut Value, bindings: &Bindings) {
+        match value {
+            Value::Var(name, span) => match bindings.get(name.as_str()).or_else(|| self.vars.get(name.as_str()).map(|(v, _)| v)) {
+                Some(v) => *value = v.clone(),
+                None => self.diagnostics.push(
+                    Diagnostic::error("E2001", format!("Undefined variable `${}`", name), *span)
+                        .with_help(format!("Declare it before use, e.g. `var {} = 100`", name))
+                ),
+            },
+            Value::Array(items) => items.iter_mut().for_each(|v| self.resolve(v, bindings)),
+            Value::Map(pairs) => pairs.iter_mut().for_each(|(_, v)| self.resolve(v, bindings)),
+            _ => {}
+        }
+    }
+
+    fn resolve_pairs(&mut self, pairs: &mut [Property]) {
+        for property in pairs {
+            self.resolve(&mut property.value, &Bindings::new());
+        }
+    }
+
+    fn resolve_attributes(&mut self, attributes: &mut [Attribute], bindings: &Bindings) {
+        for attr in attributes {
+            attr.args.iter_mut().for_each(|v| self.resolve(v, bindings));
+        }
+    }
+
+    fn expand_events(&mut self, events: Vec<Event>, bindings: &Bindings) -> Vec<Event> {
+        let mut out = Vec::with_capacity(events.len());
+        for mut event in events {
+            match &mut event {
+                Event::Note { attributes, .. } | Event::Chord { attributes, .. }
+                | Event::Tab { attributes, .. } | Event::Percussion { attributes, .. } => {
+                    self.resolve_attributes(attributes, bindings);
+                }
+                Event::Tuplet { content, .. } | Event::Transposed { content, .. } => {
+                    let events = std::mem::take(&mut content.events);
+                    content.events = self.expand_events(events, bindings);
+                }
+                Event::VoiceGroup { voices, .. } => {
+                    for voice in voices {
+                        let events = std::mem::take(&mut voice.events);
+                        voice.events = self.expand_events(events, bindings);
+                    }
+                }
+                Event::Rest { .. } => {}
+                Event::MacroCall { .. } => {
+                    out.extend(self.expand_call(event, bindings));
+                    continue;
+                }
+            }
+            out.push(event);
+        }
+        out
+    }
+
+    fn expand_call(&mut self, call: Event, bindings: &Bindings) -> Vec<Event> {
+        let Event::MacroCall { name, mut args, transpose, duration, mut attributes, span } = call else {
+            return vec![call];
+        };
+        self.resolve_attributes(&mut attributes, bindings);
+
+        // A bare `$hat` inside a macro body refers to the argument
+        if args.is_empty() && transpose == 0 {
+            if let Some(value) = bindings.get(&name) {
+                return self.substitute(&name, value, duration, attributes, span).into_iter().collect();
+            }
+        }
+
+        let Some(def) = self.macros.get(&name).cloned() else {
+            self.diagnostics.push(Diagnostic::error("E2001", format!("Undefined macro `${}`", name), span));
+            return Vec::new();
+        };
+
+        if self.stack.contains(&name) {
+            let chain: Vec<&str> = self.stack.iter().map(String::as_str).chain(std::iter::once(name.as_str())).collect();
+            self.diagnostics.push(
+                Diagnostic::fatal("E5001", format!("Circular reference: macro `${}` calls itself", name), span)
+                    .with_label(def.span, "defined here")
+                    .with_help(format!("Expansion chain: {}", chain.join(" -> ")))
+            );
+            return Vec::new();
+        }
+        if self.stack.len() >= MAX_MACRO_DEPTH {
+            self.diagnostics.push(Diagnostic::error(
+                "E5002",
+                format!("Recursion limit exceeded: expanding `${}` is nested more than {} levels deep", name, MAX_MACRO_DEPTH),
+                span,
+            ));
+            return Vec::new();
+        }
+
+        let required = def.params.iter().filter(|(_, d)| d.is_none()).count();
+        if args.len() < required || args.len() > def.params.len() {
+            let expected = if required == def.params.len() {
+                required.to_string()
+            } else {
+                format!("{} to {}", required, def.params.len())
+            };
+            self.diagnostics.push(
+                Diagnostic::error("E5003", format!("Argument mismatch: `${}` takes {} argument(s) but {} were given", name, expected, args.len()), span)
+                    .with_label(def.span, "defined here")
+            );
+            return Vec::new();
+        }
+        if duration.is_some() {
+            self.diagnostics.push(
+                Diagnostic::error("E1001", format!("Malformed token: macro call `${}` cannot take a duration", name), span)
+            );
+        }
+
+        args.iter_mut().for_each(|v| self.resolve(v, bindings));
+        let mut scope = Bindings::new();
+        for (i, (param, default)) in def.params.into_iter().enumerate() {
+            if let Some(value) = args.get(i).cloned().or(default) {
+                scope.insert(param, value);
+            }
+        }
+
+        self.stack.push(name);
+        let mut events = self.expand_events(def.body.events, &scope);
+        self.stack.pop();
+
+        // Attributes on the call apply to every event of the expansion
+        if !attributes.is_empty() {
+            append_attributes(&mut events, &attributes);
+        }
+
+        if transpose == 0 {
+            events
+        } else {
+            vec![Event::Transposed { content: Voice { id: None, events, span: def.body.span }, semitones: transpose, span }]
+        }
+    }
+
+    /// Turns an argument such as `c4`, `h_open` or `r` into an event.
+    fn substitute(&mut self, param: &str, value: &Value, duration: Option<Duration>, attributes: Vec<Attribute>, span: Span) -> Option<Event> {
+        let key = match value {
+            Value::Id(s) | Value::Str(s) => s.clone(),
+            _ => {
+                self.diagnostics.push(Diagnostic::error(
+                    "E4002",
+                    format!("Invalid type cast: argument `{}` is used as an event but is not a pitch or key", param),
+                    span,
+                ));
+                return None;
+            }
+        };
+
+        let (tokens, errors) = lexer::tokenize(&key, span.file);
+        Some(match tokens.as_slice() {
+            [(Token::PitchLit(_), _)] if errors.is_empty() => Event::Note { pitch: key, duration, attributes, tie: false, span },
+            _ if key == "r" => Event::Rest { duration, span },
+            _ => Event::Percussion { key, duration, attributes, span },
+        })
+    }
+}
+
+fn append_attributes(events: &mut [Event], extra: &[Attribute]) {
+    for event in events {
+        match event {
+            Event::Note { attributes, .. } | Event::Chord { attributes, .. }
+            | Event::Tab { attributes, .. } | Event::Percussion { attributes, .. } => {
+                attributes.extend(extra.iter().cloned());
+            }
+            Event::Tuplet { content, .. } | Event::Transposed { content, .. } => append_attributes(&mut content.events, extra),
+            Event::VoiceGroup { voices, .. } => voices.iter_mut().for_each(|v| append_attributes(&mut v.events, extra)),
+            Event::Rest { .. } | Event::MacroCall { .. } => {}
+        }
+    }
+}
@@ -0,0 +1,52 @@
+use std::ops::Range;
+
+/// Index of a source file within a compilation (the main file is `0`).
+pub type FileId = usize;
+
+/// A byte range within a specific source file.
+/// Every AST node and every `AtomicEvent` carries one, so diagnostics and
+/// editor integrations can point back at the exact source text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Span {
+    pub file: FileId,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(file: FileId, range: Range<usize>) -> Self {
+        Self { file, start: range.start, end: range.end }
+    }
+
+    pub fn range(&self) -> Range<usize> {
+        self.start..self.end
+    }
+
+    /// Smallest span covering both `self` and `other` (same file assumed).
+    pub fn union(self, other: Span) -> Span {
+        Span { file: self.file, start: self.start.min(other.start), end: self.end.max(other.end) }
+    }
+}
+
+// Lets chumsky thread the file id through the token stream.
+impl chumsky::Span for Span {
+    type Context = FileId;
+    type Offset = usize;
+
+    fn new(context: FileId, range: Range<usize>) -> Self {
+        Span::new(context, range)
+    }
+
+    fn context(&self) -> FileId { self.file }
+    fn start(&self) -> usize { self.start }
+    fn end(&self) -> usize { self.end }
+}
+
+// Lets ariadne render diagnostics straight from AST spans.
+impl ariadne::Span for Span {
+    type SourceId = FileId;
+
+    fn source(&self) -> &FileId { &self.file }
+    fn start(&self) -> usize { self.start }
+    fn end(&self) -> usize { self.end }
+}
@@ -1,7 +1,11 @@
 use tenutoc::lexer::Token;
-use tenutoc::parser::{self, Score, TopLevel, Statement, Event, Value};
-use tenutoc::ir::{self, Timeline, EventKind};
-use tenutoc::Rational;
+use tenutoc::parser::{self, BarLine, Score, TopLevel, Statement, Event, Value};
+use tenutoc::ir::{self, Curve, EventKind, Marker, MeasureInfo, TimeSignature};
+use tenutoc::{midi, playback};
+use tenutoc::{preprocess, Pipeline, Rational};
+use tenutoc::preprocess::BuildTarget;
+use tenutoc::span::Span;
+use tenutoc::diagnostic::{Severity, SourceMap};
 use logos::Logos;
 use chumsky::Parser;
 use chumsky::Stream;
@@ -86,13 +90,13 @@ fn test_lexer_operators() {
 
 fn parse_str(src: &str) -> Option<Score> {
     let lexer = Token::lexer(src);
-    let token_stream: Vec<(Token, std::ops::Range<usize>)> = lexer.spanned()
-        .map(|(tok, span)| (tok.unwrap(), span))
+    let token_stream: Vec<(Token, Span)> = lexer.spanned()
+        .map(|(tok, span)| (tok.unwrap(), Span::new(0, span)))
         .filter(|(tok, _)| *tok != Token::InvalidComment)
         .collect();
 
-    let len = src.chars().count();
-    let stream = Stream::from_iter(len..len + 1, token_stream.into_iter());
+    let len = src.len();
+    let stream = Stream::from_iter(Span::new(0, len..len + 1), token_stream.into_iter());
     
     let (ast, _errs) = parser::parser().parse_recovery(stream);
     ast
@@ -268,4 +272,1939 @@ fn test_def_attributes() {
     let track = timeline.tracks.get("pno").unwrap();
     assert_eq!(track.label, "Piano");
     assert_eq!(track.patch, "Acoustic Grand");
-}
\ No newline at end of file
+}
+
+// ========================================================================
+// 5. SOURCE SPAN TESTS
+// ========================================================================
+
+#[test]
+fn test_spans_on_ast_nodes() {
+    let src = r#"tenuto { def vln "Violin" measure 1 { vln: c4 e4.stacc | } }"#;
+    let (ast, errs) = parser::parse(src, 3);
+    assert!(errs.is_empty());
+    let ast = ast.unwrap();
+
+    assert_eq!(ast.items[0].span().file, 3);
+    assert_eq!(&src[ast.items[0].span().range()], r#"def vln "Violin""#);
+
+    if let TopLevel::Measure { content, .. } = &ast.items[1] {
+        if let Statement::Assignment { voices, .. } = &content[0] {
+            let note = &voices[0].events[1];
+            assert_eq!(&src[note.span().range()], "e4.stacc");
+            if let Event::Note { attributes, .. } = note {
+                assert_eq!(&src[attributes[0].span.range()], ".stacc");
+            } else { panic!("Expected note"); }
+        } else { panic!("Expected assignment"); }
+    } else { panic!("Expected measure"); }
+}
+
+#[test]
+fn test_spans_on_ir_events() {
+    let src = r#"tenuto { def vln "Violin" measure 1 { vln: c4:4 [e4 g4] | } }"#;
+    let ast = parse_str(src).unwrap();
+    let timeline = ir::compile(ast).unwrap();
+    let track = timeline.tracks.get("vln").unwrap();
+
+    assert_eq!(&src[track.events[0].span.range()], "c4:4");
+    // Every note of a chord points back at the whole chord
+    assert_eq!(&src[track.events[1].span.range()], "[e4 g4]");
+    assert_eq!(track.events[1].span, track.events[2].span);
+}
+
+// ========================================================================
+// 6. DIAGNOSTIC TESTS
+// ========================================================================
+
+#[test]
+fn test_diagnostic_c_style_comment() {
+    let src = "tenuto { // not a comment\n }";
+    let (_, diags) = parser::parse(src, 0);
+
+    assert_eq!(diags[0].code, "E1001");
+    assert_eq!(diags[0].severity, Severity::Error);
+    assert_eq!(&src[diags[0].span.range()], "// not a comment");
+    assert!(diags[0].help.as_ref().unwrap().contains("%%"));
+}
+
+#[test]
+fn test_diagnostic_unclosed_block() {
+    let (_, diags) = parser::parse("tenuto { measure 1 { vln: c4 |", 0);
+    assert!(diags.iter().any(|d| d.code == "E1002"));
+}
+
+#[test]
+fn test_diagnostic_undefined_staff() {
+    let src = r#"tenuto { def vln "Violin" measure 1 { vla: c4 | } }"#;
+    let diags = ir::compile(parse_str(src).unwrap()).unwrap_err();
+
+    assert_eq!(diags.len(), 1);
+    assert_eq!(diags[0].code, "E2001");
+    assert_eq!(&src[diags[0].span.range()], "vla: c4 |");
+}
+
+#[test]
+fn test_diagnostic_rendering() {
+    let src = r#"tenuto { measure 1 { vla: c4 | } }"#;
+    let mut sources = SourceMap::new();
+    let file = sources.add("score.ten", src.to_string());
+
+    let (ast, _) = parser::parse(src, file);
+    let diags = ir::compile(ast.unwrap()).unwrap_err();
+
+    let mut out = Vec::new();
+    diags[0].write(&sources, &mut out).unwrap();
+    let text = String::from_utf8(out).unwrap();
+    assert!(text.contains("E2001"));
+    assert!(text.contains("score.ten"));
+    assert!(text.contains("Undefined staff `vla`"));
+}
+
+
+// ========================================================================
+// 7. IMPORT RESOLUTION TESTS
+// ========================================================================
+
+fn fixture(name: &str) -> std::path::PathBuf {
+    std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/imports").join(name)
+}
+
+#[test]
+fn test_import_splices_files_once() {
+    let mut pipeline = Pipeline::from_file(fixture("score.ten")).unwrap();
+    let timeline = pipeline.compile().unwrap();
+
+    // orchestra.ten is imported three times but registered only once (no E2002)
+    assert!(timeline.warnings.is_empty());
+    assert_eq!(timeline.title, "Import Test");
+    assert_eq!(timeline.tracks["vln"].events.len(), 4);
+    assert_eq!(timeline.tracks["fl"].events.len(), 2);
+    assert_eq!(pipeline.sources.len(), 4);
+}
+
+#[test]
+fn test_import_spans_point_into_imported_file() {
+    let mut pipeline = Pipeline::from_file(fixture("score.ten")).unwrap();
+    let timeline = pipeline.compile().unwrap();
+
+    let span = timeline.tracks["fl"].events[0].span;
+    assert!(pipeline.sources.name(span.file).ends_with("winds.ten"));
+    assert_eq!(&pipeline.sources.text(span.file)[span.range()], "g5:2");
+}
+
+#[test]
+fn test_import_missing_file() {
+    let diags = Pipeline::from_file(fixture("missing.ten")).unwrap().compile().unwrap_err();
+    assert_eq!(diags.len(), 1);
+    assert_eq!(diags[0].code, "E2003");
+}
+
+#[test]
+fn test_import_only_in_taken_branch() {
+    // The audio build neither reads the missing engraving file nor lets the
+    // untaken branch's strings.ten import claim the duplicate guard
+    let timeline = Pipeline::from_file(fixture("conditional.ten")).unwrap().compile().unwrap();
+    assert!(timeline.warnings.is_empty());
+    assert_eq!(timeline.tracks["vln"].events.len(), 4);
+
+    let mut pipeline = Pipeline::from_file(fixture("conditional.ten")).unwrap();
+    pipeline.target.target = "score".into();
+    let diags = pipeline.compile().unwrap_err();
+    assert_eq!(diags.len(), 1);
+    assert_eq!(diags[0].code, "E2003");
+}
+
+#[test]
+fn test_import_cycle_is_broken() {
+    let (score, diags) = Pipeline::from_file(fixture("lib/cycle_a.ten")).unwrap().parse();
+
+    assert_eq!(diags.len(), 1);
+    assert_eq!(diags[0].code, "E2004");
+    assert_eq!(diags[0].severity, Severity::Error);
+    // The loop is cut, so both files are still spliced in once
+    let score = score.unwrap();
+    let staves: Vec<&str> = score.items.iter().filter_map(|item| match item {
+        TopLevel::Def { id, .. } => Some(id.as_str()),
+        _ => None,
+    }).collect();
+    assert!(staves.contains(&"vln") && staves.contains(&"vla"));
+}
+
+#[test]
+fn test_import_duplicate_definition() {
+    let diags = Pipeline::from_file(fixture("duplicate.ten")).unwrap().compile().unwrap_err();
+    assert_eq!(diags.len(), 1);
+    assert_eq!(diags[0].code, "E2002");
+    assert_eq!(diags[0].labels[0].1, "first defined here");
+}
+
+// ========================================================================
+// 8. OPEN MEASURE TESTS
+// ========================================================================
+
+fn onsets(timeline: &tenutoc::ir::Timeline, staff: &str) -> Vec<u64> {
+    timeline.tracks[staff].events.iter().map(|e| e.tick).collect()
+}
+
+#[test]
+fn test_measures_merge_across_files() {
+    let timeline = Pipeline::from_file(fixture("score.ten")).unwrap().compile().unwrap();
+
+    // strings.ten and winds.ten both write `measure 1`: they must sound together
+    assert_eq!(onsets(&timeline, "vln"), vec![0, 1920, 3840, 5760]);
+    assert_eq!(onsets(&timeline, "fl"), vec![0, 3840]);
+}
+
+#[test]
+fn test_measures_placed_by_index() {
+    let src = r#"tenuto {
+        def vln "Violin"
+        measure 3 { vln: e4:4 | }
+        measure 1 { vln: c4:4 | }
+        measure { vln: d4:4 | }
+    }"#;
+    let timeline = ir::compile(parse_str(src).unwrap()).unwrap();
+
+    // The unnumbered block follows measure 1, so it is measure 2
+    assert_eq!(onsets(&timeline, "vln"), vec![0, 7680, 15360]);
+}
+
+#[test]
+fn test_measure_length_follows_time_signature() {
+    let src = r#"tenuto {
+        meta { time: 3/4 }
+        def vln "Violin"
+        measure 1 { vln: c4:2. | }
+        measure 2 { meta { time: "2/4" } vln: d4:2 | }
+        measure 3 { vln: e4:2 | }
+    }"#;
+    let timeline = ir::compile(parse_str(src).unwrap()).unwrap();
+    assert_eq!(onsets(&timeline, "vln"), vec![0, 5760, 9600]);
+}
+
+#[test]
+fn test_measure_meta_mismatch() {
+    let diags = Pipeline::from_file(fixture("meta_clash.ten")).unwrap().compile().unwrap_err();
+    // The imported violin bar then overflows the 3/4 that measure 1 keeps
+    let codes: Vec<&str> = diags.iter().map(|d| d.code).collect();
+    assert_eq!(codes, vec!["E1601", "E3001"]);
+    assert_eq!(diags[0].labels[0].1, "previously declared here");
+}
+
+// ========================================================================
+// 9. VARIABLE TESTS
+// ========================================================================
+
+#[test]
+fn test_var_substitution() {
+    let src = r#"tenuto {
+        var Tempo = 140
+        var Loud = 115
+        meta { tempo: $Tempo }
+        def vln "Violin"
+        measure 1 { vln: c4.vel($Loud) | }
+    }"#;
+    let (score, diags) = preprocess::expand(parse_str(src).unwrap(), &BuildTarget::default());
+    assert!(diags.is_empty());
+    assert!(!score.items.iter().any(|i| matches!(i, TopLevel::Var { .. })));
+
+    let TopLevel::Measure { content, .. } = &score.items[2] else { panic!("Expected measure") };
+    let Statement::Assignment { voices, .. } = &content[0] else { panic!("Expected assignment") };
+    let Event::Note { attributes, .. } = &voices[0].events[0] else { panic!("Expected note") };
+    assert_eq!(attributes[0].args, vec![Value::Num(115)]);
+
+    let timeline = ir::compile(score).unwrap();
+    assert_eq!(timeline.tempo, 140);
+}
+
+#[test]
+fn test_var_from_import_is_global() {
+    let timeline = Pipeline::from_file(fixture("vars.ten")).unwrap().compile().unwrap();
+    assert_eq!(timeline.tempo, 96);
+}
+
+#[test]
+fn test_var_undefined() {
+    let src = "tenuto { meta { tempo: $Fast } var Fast = 180 }";
+    let diags = Pipeline::new(src.to_string()).compile().unwrap_err();
+
+    // Variables are only visible after their declaration
+    assert_eq!(diags.len(), 1);
+    assert_eq!(diags[0].code, "E2001");
+    assert_eq!(&src[diags[0].span.range()], "$Fast");
+}
+
+#[test]
+fn test_var_duplicate() {
+    let src = "tenuto { var A = 1 var A = 2 }";
+    let diags = Pipeline::new(src.to_string()).compile().unwrap_err();
+    assert_eq!(diags.len(), 1);
+    assert_eq!(diags[0].code, "E2002");
+    assert_eq!(&src[diags[0].labels[0].0.range()], "var A = 1");
+}
+
+// ========================================================================
+// 10. MACRO TESTS
+// ========================================================================
+
+fn expand_str(src: &str) -> (Score, Vec<tenutoc::diagnostic::Diagnostic>) {
+    preprocess::expand(parse_str(src).expect("Parse failed"), &BuildTarget::default())
+}
+
+fn first_voice(score: &Score) -> &[Event] {
+    let measure = score.items.iter().find_map(|i| match i {
+        TopLevel::Measure { content, .. } => Some(content),
+        _ => None,
+    }).unwrap();
+    let Statement::Assignment { voices, .. } = &measure[0] else { panic!("Expected assignment") };
+    &voices[0].events
+}
+
+#[test]
+fn test_macro_arguments_and_defaults() {
+    let src = r#"tenuto {
+        macro RockBeat(hat, v=90) = { k:4 $hat.vel($v) s:4 $hat }
+        def drm "Drums" style=grid
+        measure 1 { drm: $RockBeat(h_open, 110) | }
+        measure 2 { drm: $RockBeat(h_closed) | }
+    }"#;
+    let (score, diags) = expand_str(src);
+    assert!(diags.is_empty(), "{:?}", diags);
+
+    let events = first_voice(&score);
+    assert_eq!(events.len(), 4);
+    match &events[1] {
+        Event::Percussion { key, attributes, .. } => {
+            assert_eq!(key, "h_open");
+            assert_eq!(attributes[0].args, vec![Value::Num(110)]);
+        }
+        e => panic!("Expected percussion, got {:?}", e),
+    }
+
+    let TopLevel::Measure { content, .. } = &score.items[2] else { panic!("Expected measure 2") };
+    let Statement::Assignment { voices, .. } = &content[0] else { panic!("Expected assignment") };
+    let Event::Percussion { key, attributes, .. } = &voices[0].events[1] else { panic!("Expected percussion") };
+    assert_eq!(key, "h_closed");
+    assert_eq!(attributes[0].args, vec![Value::Num(90)]);
+}
+
+#[test]
+fn test_macro_transposition() {
+    let src = r#"tenuto {
+        macro Lick = { c4:8 d e }
+        def vln "Violin"
+        measure 1 { vln: $Lick + 5 $Lick r:4 }
+    }"#;
+    let mut pipeline = Pipeline::new(src.to_string());
+    let timeline = pipeline.compile().unwrap();
+
+    let pitches: Vec<u8> = timeline.tracks["vln"].events.iter().map(|e| match e.kind {
+        EventKind::Note { pitch, .. } => pitch,
+        _ => 0,
+    }).collect();
+    // Only pitch literals are shifted; the second call is untouched
+    assert_eq!(pitches, vec![65, 67, 69, 60, 62, 64]);
+    assert_eq!(onsets(&timeline, "vln")[3], 2880);
+}
+
+#[test]
+fn test_macro_cycle() {
+    let src = r#"tenuto {
+        macro A = { c4 $B }
+        macro B = { d4 $A }
+        def vln "Violin"
+        measure 1 { vln: $A | }
+    }"#;
+    let (_, diags) = expand_str(src);
+    assert_eq!(diags.len(), 1);
+    assert_eq!(diags[0].code, "E5001");
+    assert_eq!(diags[0].severity, Severity::Fatal);
+    assert!(diags[0].help.as_ref().unwrap().contains("A -> B -> A"));
+}
+
+#[test]
+fn test_macro_recursion_limit() {
+    let mut src = String::from("tenuto {\n");
+    for i in 0..70 {
+        src.push_str(&format!("macro M{} = {{ $M{} }}\n", i, i + 1));
+    }
+    src.push_str("macro M70 = { c4 }\ndef vln \"Violin\"\nmeasure 1 { vln: $M0 | }\n}");
+
+    let (_, diags) = expand_str(&src);
+    assert_eq!(diags.len(), 1);
+    assert_eq!(diags[0].code, "E5002");
+}
+
+#[test]
+fn test_macro_argument_mismatch() {
+    let src = r#"tenuto {
+        macro RockBeat(hat, v=90) = { k:4 $hat }
+        def drm "Drums"
+        measure 1 { drm: $RockBeat(h, 1, 2) | }
+        measure 2 { drm: $RockBeat | }
+    }"#;
+    let (_, diags) = expand_str(src);
+    assert_eq!(diags.iter().map(|d| d.code).collect::<Vec<_>>(), vec!["E5003", "E5003"]);
+    assert!(diags[0].message.contains("1 to 2"));
+}
+
+// ========================================================================
+// 11. CONDITIONAL COMPILATION TESTS
+// ========================================================================
+
+const CONDITIONAL_SRC: &str = r#"tenuto {
+    def vln "Violin"
+    def vlc "Cello"
+    if (target == "audio") { meta { tempo: 90 } } else if (target == "part") { meta { tempo: 60 } }
+    measure 1 {
+        vln: c4:1 |
+        if (target == "audio") { vlc: c2:1 | }
+        if (part_id != "vln") { vlc: g2:1 | } else { vln: e4:1 | }
+        if (debug) { vln: b4:1 | }
+    }
+}"#;
+
+fn compile_for(target: BuildTarget) -> tenutoc::ir::Timeline {
+    let mut pipeline = Pipeline::new(CONDITIONAL_SRC.to_string());
+    pipeline.target = target;
+    pipeline.compile().unwrap()
+}
+
+#[test]
+fn test_if_default_audio_target() {
+    let timeline = compile_for(BuildTarget::default());
+    assert_eq!(timeline.tempo, 90);
+    assert_eq!(timeline.tracks["vln"].events.len(), 1);
+    assert_eq!(timeline.tracks["vlc"].events.len(), 2);
+}
+
+#[test]
+fn test_if_part_target_and_debug() {
+    let timeline = compile_for(BuildTarget { target: "part".into(), part_id: Some("vln".into()), debug: true });
+    assert_eq!(timeline.tempo, 60);
+    assert_eq!(timeline.tracks["vln"].events.len(), 3);
+    assert!(timeline.tracks["vlc"].events.is_empty());
+}
+
+#[test]
+fn test_if_undefined_identifier() {
+    let src = "tenuto { if (edition == \"urtext\") { meta { tempo: 60 } } }";
+    let diags = Pipeline::new(src.to_string()).compile().unwrap_err();
+    assert_eq!(diags[0].code, "E2001");
+    assert_eq!(&src[diags[0].span.range()], "(edition == \"urtext\")");
+}
+
+// ========================================================================
+// 12. VOICE GROUP TESTS
+// ========================================================================
+
+#[test]
+fn test_voice_group_parse() {
+    let src = r#"tenuto { measure 1 { pno: { v1: c5:2 d5:2 | v2: a4:1 | } vlc: c3:1 | } }"#;
+    let ast = parse_str(src).unwrap();
+
+    let TopLevel::Measure { content, .. } = &ast.items[0] else { panic!("Expected measure") };
+    assert_eq!(content.len(), 2);
+    let Statement::Assignment { voices, .. } = &content[0] else { panic!("Expected assignment") };
+    let Event::VoiceGroup { voices: group, .. } = &voices[0].events[0] else { panic!("Expected voice group") };
+    assert_eq!(group.iter().map(|v| v.id).collect::<Vec<_>>(), vec![Some(1), Some(2)]);
+    assert_eq!(group[0].events.len(), 2);
+}
+
+#[test]
+fn test_voice_group_state_inheritance() {
+    let src = r#"tenuto {
+        def pno "Piano"
+        measure 1 {
+            pno: c5:8 { v1: d e f g | v2: c e | } a |
+        }
+    }"#;
+    let timeline = ir::compile(parse_str(src).unwrap()).unwrap();
+    let notes: Vec<(u64, u8, u8, u64)> = timeline.tracks["pno"].events.iter().map(|e| match e.kind {
+        EventKind::Note { pitch, .. } => (e.tick, pitch, e.voice, e.duration_ticks),
+        _ => panic!("Expected note"),
+    }).collect();
+
+    // v1 inherits octave 5 and the eighth; v2 resets to octave 4 and a quarter
+    assert!(notes.contains(&(960, 74, 1, 960)));
+    assert!(notes.contains(&(960, 60, 2, 1920)));
+    assert!(notes.contains(&(2880, 64, 2, 1920)));
+    // After the group the stream resumes from v1's state at the group's end
+    assert_eq!(*notes.last().unwrap(), (4800, 81, 1, 960));
+}
+
+#[test]
+fn test_pipe_segments_are_sequential() {
+    let src = r#"tenuto { def vln "Violin" measure 1 { vln: c4:4 d | e f | } }"#;
+    let timeline = ir::compile(parse_str(src).unwrap()).unwrap();
+    assert_eq!(onsets(&timeline, "vln"), vec![0, 1920, 3840, 5760]);
+    assert!(timeline.tracks["vln"].events.iter().all(|e| e.voice == 1));
+}
+
+#[test]
+fn test_voice_group_unknown_identifier() {
+    let (_, diags) = parser::parse("tenuto { measure 1 { pno: { v5: c4 | } } }", 0);
+    assert!(diags.iter().any(|d| d.message.contains("Unknown voice identifier `v5`")), "{:?}", diags);
+}
+
+// ========================================================================
+// 13. TIME INTEGRITY TESTS
+// ========================================================================
+
+fn codes(src: &str) -> Vec<&'static str> {
+    match ir::compile(parse_str(src).unwrap()) {
+        Ok(timeline) => timeline.warnings.iter().map(|d| d.code).collect(),
+        Err(diags) => diags.iter().map(|d| d.code).collect(),
+    }
+}
+
+#[test]
+fn test_voice_sync_failure() {
+    let src = r#"tenuto { def pno "Piano" measure 1 { pno: { v1: c5:2 d5:2 | v2: a4:2 | } } }"#;
+    let diags = ir::compile(parse_str(src).unwrap()).unwrap_err();
+
+    assert_eq!(diags.len(), 1);
+    assert_eq!(diags[0].code, "E3002");
+    assert!(diags[0].message.contains("v1: 1/1, v2: 1/2"));
+    assert_eq!(diags[0].labels.len(), 2);
+}
+
+#[test]
+fn test_voice_sync_with_tuplets_is_exact() {
+    // Seven 1/28 notes truncate to 274 ticks each but still fill the quarter
+    let src = r#"tenuto { def pno "Piano" measure 1 { pno: { v1: (c5:16 d e f g a b):7/4 r:2. | v2: c3:4 r:2. | } } }"#;
+    let timeline = ir::compile(parse_str(src).unwrap()).unwrap();
+    assert!(timeline.warnings.is_empty());
+
+    let short = r#"tenuto { def pno "Piano" measure 1 { pno: { v1: (c5:16 d e f g):5/4 r:2. | v2: c3:4 r:2 | } } }"#;
+    let diags = ir::compile(parse_str(short).unwrap()).unwrap_err();
+    assert_eq!(diags[0].code, "E3002");
+    assert!(diags[0].message.contains("v1: 1/1, v2: 3/4"));
+}
+
+#[test]
+fn test_voice_group_duplicate_voice() {
+    let src = r#"tenuto { def pno "Piano" measure 1 { pno: { v1: c5:1 | v1: e5:1 | } } }"#;
+    let diags = ir::compile(parse_str(src).unwrap()).unwrap_err();
+    assert_eq!(diags.len(), 1);
+    assert_eq!(diags[0].code, "E2002");
+    assert_eq!(diags[0].labels[0].1, "first defined here");
+}
+
+#[test]
+fn test_voice_group_open_tie_in_secondary_voice() {
+    let src = r#"tenuto {
+        def pno "Piano"
+        measure 1 { pno: { v1: c5:1 | v2: e4:2 g4:2~ | } }
+        measure 2 { pno: g4:1 | }
+    }"#;
+    let timeline = ir::compile(parse_str(src).unwrap()).unwrap();
+    assert_eq!(timeline.warnings.iter().map(|w| w.code).collect::<Vec<_>>(), vec!["W3007"]);
+    assert!(timeline.warnings[0].message.contains("v2"));
+    assert!(timeline.tracks["pno"].events.iter().all(|e| e.tie.is_none()));
+    let g4 = timeline.tracks["pno"].events.iter().find(|e| e.tick == 3840 && e.voice == 2).unwrap();
+    assert_eq!(g4.sounding_ticks, 3456);
+}
+
+#[test]
+fn test_time_overflow() {
+    let src = r#"tenuto {
+        meta { time: 3/4 }
+        def vln "Violin"
+        measure 1 { vln: c4:4 d e f | }
+        measure 2 { vln: c4:2. | }
+    }"#;
+    let diags = ir::compile(parse_str(src).unwrap()).unwrap_err();
+
+    assert_eq!(diags.len(), 1);
+    assert_eq!(diags[0].code, "E3001");
+    assert!(diags[0].message.contains("measure 1"));
+    assert_eq!(diags[0].help.as_deref(), Some("Remove 1/4 of events or change the time signature"));
+}
+
+#[test]
+fn test_pickup_measure() {
+    let ok = r#"tenuto {
+        def vln "Violin"
+        measure 0 { meta { time: 4/4, pickup: :8 } vln: g4:8 | }
+        measure 1 { vln: c5:1 | }
+    }"#;
+    let timeline = ir::compile(parse_str(ok).unwrap()).unwrap();
+    assert!(timeline.warnings.is_empty());
+    // Measure 1 starts right after the eighth-note anacrusis
+    assert_eq!(onsets(&timeline, "vln"), vec![0, 960]);
+
+    let short = r#"tenuto { def vln "Violin" measure 0 { meta { pickup: :4 } vln: g4:8 | } }"#;
+    assert_eq!(codes(short), vec!["W3005"]);
+}
+
+#[test]
+fn test_pickup_must_be_a_duration() {
+    let pickup = |value: &str| format!(r#"tenuto {{ def vln "Violin" measure 0 {{ meta {{ pickup: {} }} vln: g4:8 | }} }}"#, value);
+    // The colon is optional, as for `swing_grid`
+    assert!(codes(&pickup("\"8\"")).is_empty());
+    assert_eq!(codes(&pickup("\"é\"")), vec!["E4002"]);
+    assert_eq!(codes(&pickup("\"x8\"")), vec!["E4002"]);
+    assert_eq!(codes(&pickup("8")), vec!["E4002"]);
+}
+
+// ========================================================================
+// 14. MEASURE GRID TESTS
+// ========================================================================
+
+const GRID_SRC: &str = r#"tenuto {
+    meta { time: 3/4 }
+    def vln "Violin"
+    measure 0 { meta { pickup: :4 } vln: g4:4 | }
+    measure 1 { vln: c5:2. | }
+    measure 3 { meta { time: 6/8 } vln: d5:4. e5 | }
+}"#;
+
+#[test]
+fn test_measure_grid() {
+    let timeline = ir::compile(parse_str(GRID_SRC).unwrap()).unwrap();
+    let three_four = TimeSignature::new(3, 4);
+
+    assert_eq!(timeline.measures.keys().copied().collect::<Vec<_>>(), vec![0, 1, 2, 3]);
+    assert_eq!(timeline.measures[&0], MeasureInfo { start_tick: 0, length_ticks: 1920, time_signature: three_four, ..Default::default() });
+    assert_eq!(timeline.measures[&1], MeasureInfo { start_tick: 1920, length_ticks: 5760, time_signature: three_four, ..Default::default() });
+    // Measure 2 is never written but still occupies a bar
+    assert_eq!(timeline.measures[&2].start_tick, 7680);
+    assert_eq!(timeline.measures[&3], MeasureInfo { start_tick: 13440, length_ticks: 5760, time_signature: TimeSignature::new(6, 8), ..Default::default() });
+}
+
+#[test]
+fn test_midi_time_signatures() {
+    let timeline = ir::compile(parse_str(GRID_SRC).unwrap()).unwrap();
+    let bytes = midi::export(&timeline).unwrap();
+    let smf = midly::Smf::parse(&bytes).unwrap();
+
+    let mut tick = 0;
+    let mut signatures = Vec::new();
+    for event in &smf.tracks[0] {
+        tick += event.delta.as_int();
+        if let midly::TrackEventKind::Meta(midly::MetaMessage::TimeSignature(n, d, _, _)) = event.kind {
+            signatures.push((tick, n, d));
+        }
+    }
+    // The quarter-note pickup is written as 1/4, then 3/4 from measure 1.
+    // 6/8 is written as 6 over 2^3
+    assert_eq!(signatures, vec![(0, 1, 2), (1920, 3, 2), (13440, 6, 3)]);
+}
+
+#[test]
+fn test_midi_pickup_keeps_later_meters_on_bar_lines() {
+    let src = r#"tenuto {
+        meta { time: "3/4" }
+        def vln "Violin"
+        measure 0 { meta { pickup: ":8" } vln: g4:8 | }
+        measure 1 { vln: c5:2. | }
+        measure 2 { meta { time: "2/4" } vln: d5:2 | }
+    }"#;
+    let timeline = ir::compile(parse_str(src).unwrap()).unwrap();
+    let bytes = midi::export(&timeline).unwrap();
+    let smf = midly::Smf::parse(&bytes).unwrap();
+    let mut tick = 0;
+    let mut signatures = Vec::new();
+    for event in &smf.tracks[0] {
+        tick += event.delta.as_int();
+        if let midly::TrackEventKind::Meta(midly::MetaMessage::TimeSignature(n, d, _, _)) = event.kind {
+            signatures.push((tick, n, d));
+        }
+    }
+    // An eighth pickup is 1/8; the 2/4 change lands on measure 2's bar line
+    assert_eq!(signatures, vec![(0, 1, 3), (960, 3, 2), (6720, 2, 2)]);
+}
+
+// ========================================================================
+// 15. TEMPO MAP TESTS
+// ========================================================================
+
+#[test]
+fn test_curve_shapes() {
+    assert_eq!(Curve::Linear.interpolate(120.0, 60.0, 0.5), 90.0);
+    assert_eq!(Curve::Step.interpolate(120.0, 60.0, 0.99), 120.0);
+    assert_eq!(Curve::Step.interpolate(120.0, 60.0, 1.0), 60.0);
+    // exp starts slowly, log starts quickly; both hit the endpoints
+    assert!(Curve::Exp.interpolate(0.0, 100.0, 0.5) < 50.0);
+    assert!(Curve::Log.interpolate(0.0, 100.0, 0.5) > 50.0);
+    assert!((Curve::Exp.interpolate(0.0, 100.0, 1.0) - 100.0).abs() < 1e-9);
+    assert!((Curve::Log.interpolate(0.0, 100.0, 1.0) - 100.0).abs() < 1e-9);
+}
+
+const TEMPO_SRC: &str = r#"tenuto {
+    meta { tempo: 120 }
+    def vln "Violin"
+    measure 1 { vln: c4:1 | }
+    measure 2 { meta { tempo: 60 } vln: c4:1 | }
+    measure 3 { meta { tempo: [60, 120], curve: "linear" } vln: c4:1 | }
+    measure 4 { vln: c4:1 | }
+}"#;
+
+#[test]
+fn test_tempo_map_conversion() {
+    let timeline = ir::compile(parse_str(TEMPO_SRC).unwrap()).unwrap();
+    assert_eq!(timeline.tempo, 120);
+    assert_eq!(timeline.tempo_map.segments.len(), 3);
+
+    // Bar 1 at 120 BPM = 2s, bar 2 at 60 BPM = 4s
+    assert!((timeline.tick_to_seconds(7680) - 2.0).abs() < 1e-9);
+    assert!((timeline.tick_to_seconds(15360) - 6.0).abs() < 1e-9);
+    // Linear 60 -> 120 over 4 beats lasts 4 * ln(2) seconds
+    let ramp_end = 6.0 + 4.0 * std::f64::consts::LN_2;
+    assert!((timeline.tick_to_seconds(23040) - ramp_end).abs() < 1e-6);
+    // Bar 4 holds the ramp's final tempo
+    assert!((timeline.tick_to_seconds(30720) - (ramp_end + 2.0)).abs() < 1e-6);
+
+    assert_eq!(timeline.seconds_to_tick(6.0), 15360);
+    assert_eq!(timeline.seconds_to_tick(ramp_end + 1.0), 26880);
+}
+
+#[test]
+fn test_midi_tempo_ramp_is_dense() {
+    let timeline = ir::compile(parse_str(TEMPO_SRC).unwrap()).unwrap();
+    let bytes = midi::export(&timeline).unwrap();
+    let smf = midly::Smf::parse(&bytes).unwrap();
+
+    let mut tick = 0;
+    let mut tempos = Vec::new();
+    for event in &smf.tracks[0] {
+        tick += event.delta.as_int();
+        if let midly::TrackEventKind::Meta(midly::MetaMessage::Tempo(mpq)) = event.kind {
+            tempos.push((tick, mpq.as_int()));
+        }
+    }
+    assert_eq!(tempos[0], (0, 500_000));
+    assert_eq!(tempos[1], (7680, 1_000_000));
+    assert_eq!(*tempos.last().unwrap(), (23040, 500_000));
+    // One event per 32nd note across the four-beat ramp
+    assert_eq!(tempos.len(), 2 + 32);
+}
+
+#[test]
+fn test_tempo_invalid_curve() {
+    let src = r#"tenuto { def vln "V" measure 1 { meta { tempo: [60, 90], curve: "wobble" } vln: c4:1 | } }"#;
+    let diags = ir::compile(parse_str(src).unwrap()).unwrap_err();
+    assert_eq!(diags[0].code, "E4002");
+}
+
+#[test]
+fn test_tempo_must_be_positive() {
+    let top = |v: &str| format!(r#"tenuto {{ meta {{ tempo: {} }} def vln "V" measure 1 {{ vln: c4:1 | }} }}"#, v);
+    let bar = |v: &str| format!(r#"tenuto {{ def vln "V" measure 1 {{ meta {{ tempo: {} }} vln: c4:1 | }} }}"#, v);
+    for v in ["0", "-5", "[60, 90]", "\"fast\""] {
+        assert_eq!(codes(&top(v)), vec!["E4002"], "top-level {}", v);
+    }
+    for v in ["0", "-5", "[60, 0]"] {
+        assert_eq!(codes(&bar(v)), vec!["E4002"], "measure {}", v);
+    }
+}
+
+// ========================================================================
+// 16. DYNAMICS TESTS
+// ========================================================================
+
+fn velocities(timeline: &tenutoc::ir::Timeline, staff: &str) -> Vec<u8> {
+    timeline.tracks[staff].events.iter().filter_map(|e| match e.kind {
+        EventKind::Note { velocity, .. } => Some(velocity),
+        _ => None,
+    }).collect()
+}
+
+#[test]
+fn test_lexer_duration_before_attribute() {
+    let lex = |src: &str| Token::lexer(src).map(|t| t.unwrap()).collect::<Vec<_>>();
+
+    // The dot belongs to the attribute, not the duration
+    assert_eq!(lex(":4.stacc"), vec![Token::DurationLit(":4".into()), Token::Dot, Token::Identifier("stacc".into())]);
+    assert_eq!(lex(":4..f"), vec![Token::DurationLit(":4.".into()), Token::Dot, Token::PitchLit("f".into())]);
+    assert_eq!(lex(":8. d"), vec![Token::DurationLit(":8.".into()), Token::PitchLit("d".into())]);
+}
+
+#[test]
+fn test_dynamics_are_sticky() {
+    let src = r#"tenuto {
+        def vln "Violin"
+        measure 1 { vln: c4:4 d.pp e f.f | }
+        measure 2 { vln: g4:4.mf a [c e g].ffff r |}
+    }"#;
+    let timeline = ir::compile(parse_str(src).unwrap()).unwrap();
+    assert_eq!(velocities(&timeline, "vln"), vec![100, 40, 40, 96, 80, 80, 127, 127, 127]);
+}
+
+#[test]
+fn test_velocity_override_and_accents() {
+    let src = r#"tenuto {
+        def vln "Violin"
+        measure 1 { vln: c4:4.p d.vel(120) e.sfz f | }
+        measure 2 { vln: c4:4.fp d e.vel(200) f | }
+    }"#;
+    let timeline = ir::compile(parse_str(src).unwrap()).unwrap();
+
+    // `.vel` applies to one note only; `sfz` and `fp` are sticky like any dynamic
+    assert_eq!(velocities(&timeline, "vln"), vec![52, 120, 112, 112, 96, 96, 127, 96]);
+    assert_eq!(timeline.warnings.len(), 1);
+    assert_eq!(timeline.warnings[0].code, "W4003");
+}
+
+#[test]
+fn test_dynamics_shared_across_voice_group() {
+    let src = r#"tenuto {
+        def pno "Piano"
+        measure 1 { pno: c4:2.pp { v1: d4:4 e | v2: g3:2 | } | }
+    }"#;
+    let timeline = ir::compile(parse_str(src).unwrap()).unwrap();
+    assert_eq!(velocities(&timeline, "pno"), vec![40, 40, 40, 40]);
+}
+
+// ========================================================================
+// 17. ARTICULATION TESTS
+// ========================================================================
+
+#[test]
+fn test_articulation_gate_times() {
+    let src = r#"tenuto {
+        def vln "Violin"
+        measure 1 { vln: c4:4.stacc d.stacciss e.ten f | }
+    }"#;
+    let timeline = ir::compile(parse_str(src).unwrap()).unwrap();
+    let events = &timeline.tracks["vln"].events;
+
+    // Notated lengths are untouched; only the sounding length is gated
+    assert!(events.iter().all(|e| e.duration_ticks == 1920));
+    let sounding: Vec<u64> = events.iter().map(|e| e.sounding_ticks).collect();
+    assert_eq!(sounding, vec![960, 480, 1920, 1728]);
+}
+
+#[test]
+fn test_accents_boost_velocity() {
+    let src = r#"tenuto {
+        def vln "Violin"
+        measure 1 { vln: c4:4.mf.acc d.marc e [c e g].ffff.marc | }
+    }"#;
+    let timeline = ir::compile(parse_str(src).unwrap()).unwrap();
+    assert_eq!(velocities(&timeline, "vln"), vec![92, 100, 80, 127, 127, 127]);
+}
+
+#[test]
+fn test_midi_note_off_uses_sounding_length() {
+    let src = r#"tenuto {
+        def vln "Violin"
+        measure 1 { vln: c4:2.stacc d:2 | }
+    }"#;
+    let timeline = ir::compile(parse_str(src).unwrap()).unwrap();
+    let bytes = midi::export(&timeline).unwrap();
+    let smf = midly::Smf::parse(&bytes).unwrap();
+
+    let mut tick = 0;
+    let mut offs = Vec::new();
+    for event in &smf.tracks[1] {
+        tick += event.delta.as_int();
+        if let midly::TrackEventKind::Midi { message: midly::MidiMessage::NoteOff { .. }, .. } = event.kind {
+            offs.push(tick);
+        }
+    }
+    assert_eq!(offs, vec![1920, 3840 + 3456]);
+}
+
+// ========================================================================
+// 18. TECHNIQUE & KEYSWITCH TESTS
+// ========================================================================
+
+/// Keyswitches at the tick they are sent, ahead of their note.
+fn keyswitches(timeline: &tenutoc::ir::Timeline, staff: &str) -> Vec<(u64, u8)> {
+    timeline.tracks[staff].events.iter().filter_map(|e| match e.kind {
+        EventKind::KeySwitch { pitch } => Some(((e.tick as i64 + e.offset_ticks).max(0) as u64, pitch)),
+        _ => None,
+    }).collect()
+}
+
+#[test]
+fn test_parser_def_map_attribute() {
+    let src = r#"tenuto {
+        def vln "Violin" style=standard keyswitch={
+            arco: 24,   %% C0
+            pizz: 25,
+        }
+    }"#;
+    let ast = parse_str(src).unwrap();
+    let TopLevel::Def { attributes, .. } = &ast.items[0] else { panic!("Expected def") };
+    assert_eq!(attributes[1].key, "keyswitch");
+    assert_eq!(attributes[1].value, Value::Map(vec![
+        ("arco".to_string(), Value::Num(24)),
+        ("pizz".to_string(), Value::Num(25)),
+    ]));
+}
+
+#[test]
+fn test_keyswitch_emitted_on_technique_change() {
+    let src = r#"tenuto {
+        def vln "Violin" keyswitch={ arco: 24, pizz: 25, trem: 30 }
+        measure 1 { vln: c4:4.pizz d e.pizz f.arco | }
+        measure 2 { vln: g4:4.mute a.trem b.trem c5.arco | }
+    }"#;
+    let timeline = ir::compile(parse_str(src).unwrap()).unwrap();
+
+    // Sent 1/64 of a beat early, once per change; `mute` has no mapping
+    assert_eq!(keyswitches(&timeline, "vln"), vec![(0, 25), (5730, 24), (9570, 30), (13410, 24)]);
+    assert_eq!(velocities(&timeline, "vln").len(), 8);
+}
+
+#[test]
+fn test_keyswitch_in_midi_export() {
+    let src = r#"tenuto {
+        def vln "Violin" keyswitch={ pizz: 25 }
+        measure 1 { vln: c4:1.pizz | }
+    }"#;
+    let timeline = ir::compile(parse_str(src).unwrap()).unwrap();
+    let bytes = midi::export(&timeline).unwrap();
+    let smf = midly::Smf::parse(&bytes).unwrap();
+
+    let ons: Vec<(u8, u8)> = smf.tracks[1].iter().filter_map(|e| match e.kind {
+        midly::TrackEventKind::Midi { message: midly::MidiMessage::NoteOn { key, vel }, .. } => Some((key.as_int(), vel.as_int())),
+        _ => None,
+    }).collect();
+    assert_eq!(ons, vec![(25, 1), (60, 100)]);
+}
+
+#[test]
+fn test_invalid_keyswitch_map() {
+    let src = r#"tenuto {
+        def vln "Violin" keyswitch={ arco: "low", pizz: 300 }
+    }"#;
+    let errors = ir::compile(parse_str(src).unwrap()).unwrap_err();
+    let codes: Vec<&str> = errors.iter().map(|d| d.code).collect();
+    assert_eq!(codes, vec!["E4002", "W4003"]);
+}
+
+// ========================================================================
+// 19. TABLATURE TESTS
+// ========================================================================
+
+fn pitches(timeline: &tenutoc::ir::Timeline, staff: &str) -> Vec<u8> {
+    timeline.tracks[staff].events.iter().filter_map(|e| match e.kind {
+        EventKind::Note { pitch, .. } => Some(pitch),
+        _ => None,
+    }).collect()
+}
+
+#[test]
+fn test_tab_resolves_against_tuning() {
+    let src = r#"tenuto {
+        def gtr "Guitar" style=tab tuning=guitar_std
+        measure 1 { gtr: 0-6:4 3-5 2-1 12-3 | }
+    }"#;
+    let timeline = ir::compile(parse_str(src).unwrap()).unwrap();
+    assert_eq!(pitches(&timeline, "gtr"), vec![40, 48, 66, 67]);
+
+    // Coordinates survive for tab export
+    let first = &timeline.tracks["gtr"].events[0];
+    assert_eq!(first.tab, Some(ir::TabPosition { fret: 0, string: 6 }));
+}
+
+#[test]
+fn test_tab_custom_tuning_and_capo() {
+    let src = r#"tenuto {
+        def bass "Bass" style=tab tuning=[D1, A1, D2, G2] capo=2
+        measure 1 { bass: 0-4:4 0-1 5-2.p 1-3 | }
+    }"#;
+    let timeline = ir::compile(parse_str(src).unwrap()).unwrap();
+    assert_eq!(pitches(&timeline, "bass"), vec![28, 45, 45, 36]);
+    // A pull-off is not a piano marking
+    assert_eq!(velocities(&timeline, "bass"), vec![100; 4]);
+}
+
+#[test]
+fn test_tab_missing_tuning_warns() {
+    let src = r#"tenuto {
+        def gtr "Guitar" style=tab
+        measure 1 { gtr: 0-1:1 | }
+    }"#;
+    let timeline = ir::compile(parse_str(src).unwrap()).unwrap();
+    assert_eq!(pitches(&timeline, "gtr"), vec![64]);
+    assert_eq!(timeline.warnings.len(), 1);
+    assert_eq!(timeline.warnings[0].code, "W4003");
+    assert!(timeline.warnings[0].message.contains("no tuning"));
+}
+
+#[test]
+fn test_tab_on_non_tab_staff_warns() {
+    let src = r#"tenuto {
+        def vln "Violin"
+        measure 1 { vln: 0-1:4 3-2 c4 r | }
+    }"#;
+    let timeline = ir::compile(parse_str(src).unwrap()).unwrap();
+    assert_eq!(pitches(&timeline, "vln"), vec![64, 62, 60]);
+    // Reported once for the staff
+    assert_eq!(timeline.warnings.iter().map(|w| w.code).collect::<Vec<_>>(), vec!["W4003"]);
+    assert!(timeline.warnings[0].message.contains("non-tab staff"));
+}
+
+#[test]
+fn test_transposed_tab_warns() {
+    let staff = r#"tenuto {
+        def gtr "Guitar" style=tab tuning=guitar_std transpose=-12
+        measure 1 { gtr: 0-6:1 | }
+    }"#;
+    let timeline = ir::compile(parse_str(staff).unwrap()).unwrap();
+    assert_eq!(pitches(&timeline, "gtr"), vec![40]);
+    assert_eq!(timeline.warnings[0].code, "W4003");
+    assert!(timeline.warnings[0].message.contains("`transpose` ignored"));
+
+    let riff = r#"tenuto {
+        macro Riff = { 0-6:4 3-6 5-5 r }
+        def gtr "Guitar" style=tab tuning=guitar_std
+        measure 1 { gtr: $Riff + 2 | }
+    }"#;
+    let timeline = Pipeline::new(riff.to_string()).compile().unwrap();
+    assert_eq!(pitches(&timeline, "gtr"), vec![40, 43, 50]);
+    assert_eq!(timeline.warnings.iter().map(|w| w.code).collect::<Vec<_>>(), vec!["W4003"]);
+    assert!(timeline.warnings[0].message.contains("transposition ignored"));
+}
+
+#[test]
+fn test_tab_string_out_of_range() {
+    let src = r#"tenuto {
+        def bass "Bass" style=tab tuning=bass_std
+        measure 1 { bass: 0-4:2 3-5 | }
+    }"#;
+    let errors = ir::compile(parse_str(src).unwrap()).unwrap_err();
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].code, "E801");
+    assert!(errors[0].message.contains("string 5"));
+}
+
+// ========================================================================
+// 20. PERCUSSION GRID TESTS
+// ========================================================================
+
+fn channels(bytes: &[u8]) -> Vec<u8> {
+    let smf = midly::Smf::parse(bytes).unwrap();
+    smf.tracks[1..].iter().map(|track| {
+        track.iter().find_map(|e| match e.kind {
+            midly::TrackEventKind::Midi { channel, .. } => Some(channel.as_int()),
+            _ => None,
+        }).unwrap()
+    }).collect()
+}
+
+#[test]
+fn test_grid_gm_kit_default() {
+    let src = r#"tenuto {
+        def drm "Drums" style=grid
+        measure 1 { drm: k:8 h s h k k s ho | }
+    }"#;
+    let timeline = ir::compile(parse_str(src).unwrap()).unwrap();
+    assert_eq!(pitches(&timeline, "drm"), vec![36, 42, 38, 42, 36, 36, 38, 46]);
+}
+
+#[test]
+fn test_grid_custom_map() {
+    let src = r#"tenuto {
+        def perc "Percussion" style=grid map={ kick: [0, 35], clap: [4, 39] }
+        measure 1 { perc: kick:4 clap.ff kick clap | }
+    }"#;
+    let timeline = ir::compile(parse_str(src).unwrap()).unwrap();
+    assert_eq!(pitches(&timeline, "perc"), vec![35, 39, 35, 39]);
+    assert_eq!(velocities(&timeline, "perc"), vec![100, 108, 108, 108]);
+}
+
+#[test]
+fn test_grid_unknown_key() {
+    let src = r#"tenuto {
+        def perc "Percussion" style=grid map={ kick: [0, 35] }
+        measure 1 { perc: kick:4 snare kick kick | }
+    }"#;
+    let errors = ir::compile(parse_str(src).unwrap()).unwrap_err();
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].code, "E4004");
+    assert_eq!(errors[0].help.as_deref(), Some("Defined keys: kick"));
+}
+
+#[test]
+fn test_grid_routes_to_channel_ten() {
+    let src = r#"tenuto {
+        def pno "Piano"
+        def vln "Violin" channel=4
+        def drm "Drums" style=grid
+        def perc "Percussion" style=grid channel=11
+        measure 1 { drm: k:1 | perc: k:1 | pno: c4:1 | vln: c4:1 | }
+    }"#;
+    let timeline = ir::compile(parse_str(src).unwrap()).unwrap();
+    let bytes = midi::export(&timeline).unwrap();
+    // Tracks are exported in staff-id order: drm, perc, pno, vln
+    assert_eq!(channels(&bytes), vec![9, 10, 0, 3]);
+}
+
+// ========================================================================
+// 21. STYLE-AWARE INTERPRETATION TESTS
+// ========================================================================
+
+#[test]
+fn test_grid_keys_that_lex_as_pitches() {
+    let src = r#"tenuto {
+        def vln "Violin"
+        def drm "Drums" style=grid map={ k: [1, 36], c: [10, 49], cb: [9, 56] }
+        measure 1 { vln: c4:4 d e f | drm: [k c]:4 k cb c | }
+    }"#;
+    let timeline = ir::compile(parse_str(src).unwrap()).unwrap();
+    assert_eq!(pitches(&timeline, "vln"), vec![60, 62, 64, 65]);
+    assert_eq!(pitches(&timeline, "drm"), vec![36, 49, 36, 56, 49]);
+    assert_eq!(onsets(&timeline, "drm"), vec![0, 0, 1920, 3840, 5760]);
+}
+
+#[test]
+fn test_grid_ride_key_is_not_a_rest() {
+    let src = r#"tenuto {
+        def drm "Drums" style=grid
+        def perc "Percussion" style=grid map={ k: [1, 36] }
+        measure 1 { drm: r:4 r k r | perc: k:4 r k r | }
+    }"#;
+    let timeline = ir::compile(parse_str(src).unwrap()).unwrap();
+    assert_eq!(pitches(&timeline, "drm"), vec![51, 51, 36, 51]);
+    let bytes = midi::export(&timeline).unwrap();
+    assert_eq!(note_ons(&bytes, 1), vec![(0, 51), (1920, 51), (3840, 36), (5760, 51)]);
+    // Without an `r` key in the map it is still a rest
+    assert_eq!(onsets(&timeline, "perc"), vec![0, 3840]);
+}
+
+#[test]
+fn test_pitch_like_staff_ids() {
+    let src = r#"tenuto {
+        def a "Alto Sax"
+        def bb "Bass"
+        measure 1 { a: c5:2 d | bb: e2:1 | }
+    }"#;
+    let timeline = ir::compile(parse_str(src).unwrap()).unwrap();
+    assert_eq!(pitches(&timeline, "a"), vec![72, 74]);
+    assert_eq!(pitches(&timeline, "bb"), vec![40]);
+}
+
+#[test]
+fn test_drum_key_on_standard_staff() {
+    let src = r#"tenuto {
+        def vln "Violin"
+        measure 1 { vln: [c e k]:2 snare | }
+    }"#;
+    let errors = ir::compile(parse_str(src).unwrap()).unwrap_err();
+    let codes: Vec<&str> = errors.iter().map(|d| d.code).collect();
+    assert_eq!(codes, vec!["E1001", "E4004"]);
+}
+
+// ========================================================================
+// 22. TIE TESTS
+// ========================================================================
+
+fn note_ons(bytes: &[u8], track: usize) -> Vec<(u32, u8)> {
+    let smf = midly::Smf::parse(bytes).unwrap();
+    let mut tick = 0;
+    let mut ons = Vec::new();
+    for event in &smf.tracks[track] {
+        tick += event.delta.as_int();
+        if let midly::TrackEventKind::Midi { message: midly::MidiMessage::NoteOn { key, .. }, .. } = event.kind {
+            ons.push((tick, key.as_int()));
+        }
+    }
+    ons
+}
+
+#[test]
+fn test_parser_tie_syntax() {
+    let src = "tenuto { def p \"Piano\" measure 1 { p: c4:4~ c4:8.stacc~ c [c4~ e4 g4]:8 [d f]:8~ | } }";
+    let ast = parse_str(src).unwrap();
+    let TopLevel::Measure { content, .. } = &ast.items[1] else { panic!("Expected measure") };
+    let Statement::Assignment { voices, .. } = &content[0] else { panic!("Expected assignment") };
+
+    let ties: Vec<Vec<bool>> = voices[0].events.iter().map(|e| match e {
+        Event::Note { tie, .. } => vec![*tie],
+        Event::Chord { ties, .. } => ties.clone(),
+        _ => panic!("Unexpected event"),
+    }).collect();
+    assert_eq!(ties, vec![vec![true], vec![true], vec![false], vec![true, false, false], vec![true, true]]);
+}
+
+#[test]
+fn test_tied_notes_sound_once() {
+    let src = r#"tenuto {
+        def vln "Violin"
+        measure 1 { vln: c4:2~ c4:4~ c4:8 d4:8 | }
+        measure 2 { vln: e4:1~ | }
+        measure 3 { vln: e4:2 r:2 | }
+    }"#;
+    let timeline = ir::compile(parse_str(src).unwrap()).unwrap();
+    let events = &timeline.tracks["vln"].events;
+
+    // Notation keeps every note and its role in the chain
+    let ties: Vec<Option<ir::Tie>> = events.iter().map(|e| e.tie).collect();
+    use ir::Tie::*;
+    assert_eq!(ties, vec![Some(Start), Some(Continue), Some(Stop), None, Some(Start), Some(Stop)]);
+    assert_eq!(events[0].duration_ticks, 3840);
+    // c4 lasts 2 + 1 beats + 90% of an eighth; e4 crosses the bar line
+    assert_eq!(events[0].sounding_ticks, 3840 + 1920 + 864);
+    assert_eq!(events[4].sounding_ticks, 7680 + 3456);
+
+    let bytes = midi::export(&timeline).unwrap();
+    assert_eq!(note_ons(&bytes, 1), vec![(0, 60), (6720, 62), (7680, 64)]);
+}
+
+#[test]
+fn test_chord_ties_per_note() {
+    let src = r#"tenuto {
+        def pno "Piano"
+        measure 1 { pno: [c4~ e4 g4]:2 [c4 f4 a4]:2 | }
+    }"#;
+    let timeline = ir::compile(parse_str(src).unwrap()).unwrap();
+    let bytes = midi::export(&timeline).unwrap();
+    assert_eq!(note_ons(&bytes, 1), vec![(0, 60), (0, 64), (0, 67), (3840, 65), (3840, 69)]);
+
+    let c4 = &timeline.tracks["pno"].events[0];
+    assert_eq!(c4.sounding_ticks, 3840 + 3456);
+}
+
+#[test]
+fn test_broken_tie_warns() {
+    let src = r#"tenuto {
+        def vln "Violin"
+        measure 1 { vln: c4:2~ d4:2 | }
+    }"#;
+    let timeline = ir::compile(parse_str(src).unwrap()).unwrap();
+    assert_eq!(timeline.warnings.len(), 1);
+    assert_eq!(timeline.warnings[0].code, "W3007");
+    // The tie is dropped and both notes attack
+    assert!(timeline.tracks["vln"].events.iter().all(|e| e.tie.is_none()));
+    let bytes = midi::export(&timeline).unwrap();
+    assert_eq!(note_ons(&bytes, 1), vec![(0, 60), (3840, 62)]);
+    // The orphaned note is gated like any other
+    assert_eq!(note_spans(&bytes, 1)[0], (0, 3456, 60));
+
+    // A chain cut short ends at its last note's own articulation
+    let src = r#"tenuto {
+        def vln "Violin"
+        measure 1 { vln: c4:2~ c4:4.stacc~ d4:4 | }
+    }"#;
+    let timeline = ir::compile(parse_str(src).unwrap()).unwrap();
+    assert_eq!(timeline.warnings[0].code, "W3007");
+    assert_eq!(timeline.tracks["vln"].events[0].sounding_ticks, 3840 + 960);
+}
+
+// ========================================================================
+// 23. GRACE NOTE TESTS
+// ========================================================================
+
+/// (onset, offset, key) of every note in a MIDI track, ordered by onset.
+fn note_spans(bytes: &[u8], track: usize) -> Vec<(u32, u32, u8)> {
+    let smf = midly::Smf::parse(bytes).unwrap();
+    let mut tick = 0;
+    let mut open = Vec::new();
+    let mut spans = Vec::new();
+    for event in &smf.tracks[track] {
+        tick += event.delta.as_int();
+        match event.kind {
+            midly::TrackEventKind::Midi { message: midly::MidiMessage::NoteOn { key, .. }, .. } => open.push((tick, key.as_int())),
+            midly::TrackEventKind::Midi { message: midly::MidiMessage::NoteOff { key, .. }, .. } => {
+                let i = open.iter().position(|&(_, k)| k == key.as_int()).unwrap();
+                let (on, key) = open.remove(i);
+                spans.push((on, tick, key));
+            }
+            _ => {}
+        }
+    }
+    spans.sort();
+    spans
+}
+
+#[test]
+fn test_lexer_grace_durations() {
+    let lex = |src: &str| Token::lexer(src).map(|t| t.unwrap()).collect::<Vec<_>>();
+    assert_eq!(lex("d:grace.slash"), vec![Token::PitchLit("d".into()), Token::DurationLit(":grace.slash".into())]);
+    assert_eq!(lex(":grace.noSlash"), vec![Token::DurationLit(":grace.noSlash".into())]);
+    assert_eq!(lex(":grace.stacc"), vec![Token::DurationLit(":grace".into()), Token::Dot, Token::Identifier("stacc".into())]);
+}
+
+#[test]
+fn test_grace_notes_take_no_metric_time() {
+    let src = r#"tenuto {
+        def vln "Violin"
+        measure 1 { vln: c4:4 d:grace e [f a]:grace g b | }
+    }"#;
+    let timeline = ir::compile(parse_str(src).unwrap()).unwrap();
+    let events = &timeline.tracks["vln"].events;
+
+    // `e` keeps the sticky quarter and the bar is not overfull
+    assert_eq!(onsets(&timeline, "vln"), vec![0, 1920, 1920, 3840, 3840, 3840, 5760]);
+    let graces: Vec<Option<ir::Grace>> = events.iter().map(|e| e.grace).collect();
+    use ir::Grace::Acciaccatura as A;
+    assert_eq!(graces, vec![None, Some(A), None, Some(A), Some(A), None, None]);
+    assert_eq!(events[1].duration_ticks, 0);
+    assert_eq!(events[2].duration_ticks, 1920);
+}
+
+#[test]
+fn test_grace_steals_from_following_note() {
+    let src = r#"tenuto {
+        def vln "Violin"
+        measure 1 { vln: c4:2 d:grace.noSlash e:grace.noSlash f:2.ten | }
+    }"#;
+    let timeline = ir::compile(parse_str(src).unwrap()).unwrap();
+    let bytes = midi::export(&timeline).unwrap();
+    assert_eq!(note_spans(&bytes, 1), vec![
+        (0, 3456, 60),
+        (3840, 4080, 62),
+        (4080, 4320, 64),
+        (4320, 7680, 65),
+    ]);
+    assert_eq!(timeline.tracks["vln"].events[2].grace, Some(ir::Grace::Appoggiatura));
+}
+
+#[test]
+fn test_slashed_grace_steals_from_previous_note() {
+    let src = r#"tenuto {
+        def vln "Violin"
+        measure 1 { vln: c4:2.ten d:grace.slash e:2 | }
+    }"#;
+    let timeline = ir::compile(parse_str(src).unwrap()).unwrap();
+    let bytes = midi::export(&timeline).unwrap();
+    assert_eq!(note_spans(&bytes, 1), vec![(0, 3600, 60), (3600, 3840, 62), (3840, 7296, 64)]);
+}
+
+#[test]
+fn test_grace_at_end_of_underfull_bar_leans_on_next_bar() {
+    let src = r#"tenuto {
+        def vln "Violin"
+        measure 1 { vln: c4:2 d:grace | }
+        measure 2 { vln: e4:1 | }
+    }"#;
+    let timeline = ir::compile(parse_str(src).unwrap()).unwrap();
+    assert!(timeline.warnings.is_empty());
+    let bytes = midi::export(&timeline).unwrap();
+    assert_eq!(note_spans(&bytes, 1), vec![(0, 3456, 60), (7680, 7920, 62), (7920, 14592, 64)]);
+}
+
+#[test]
+fn test_orphaned_grace_warns() {
+    let src = r#"tenuto {
+        def pno "Piano"
+        measure 1 { pno: { v1: c5:1 | v2: a3:1 b3:grace | } }
+        measure 2 { pno: c5:1 f4:grace | }
+    }"#;
+    let timeline = ir::compile(parse_str(src).unwrap()).unwrap();
+    let orphans: Vec<&str> = timeline.warnings.iter()
+        .filter(|w| w.code == "W3008")
+        .map(|w| &src[w.span.range()])
+        .collect();
+    assert_eq!(orphans, vec!["b3:grace", "f4:grace"]);
+    // The v2 grace still sounds, at its own tick
+    let spans = note_spans(&midi::export(&timeline).unwrap(), 1);
+    assert!(spans.contains(&(7680, 7920, 59)));
+}
+
+#[test]
+fn test_grace_on_grid_staff() {
+    let src = r#"tenuto {
+        def drm "Drums" style=grid
+        measure 1 { drm: s:grace s:4 k k k | }
+    }"#;
+    let timeline = ir::compile(parse_str(src).unwrap()).unwrap();
+    assert_eq!(timeline.tracks["drm"].events[0].grace, Some(ir::Grace::Acciaccatura));
+    // The flam is heard before the principal instead of cutting it off
+    let spans = note_spans(&midi::export(&timeline).unwrap(), 1);
+    assert_eq!(spans[0], (0, 240, 38));
+    assert_eq!((spans[1].0, spans[1].2), (240, 38));
+    assert!(spans[1].1 > 240);
+}
+
+#[test]
+fn test_grace_on_tab_staff() {
+    let src = r#"tenuto {
+        def gtr "Guitar" style=tab tuning=guitar_std
+        measure 1 { gtr: 3-6:grace 3-6:4 5-5 5-5 5-5 | }
+    }"#;
+    let timeline = ir::compile(parse_str(src).unwrap()).unwrap();
+    assert_eq!(timeline.tracks["gtr"].events[0].grace, Some(ir::Grace::Acciaccatura));
+    let spans = note_spans(&midi::export(&timeline).unwrap(), 1);
+    assert_eq!(spans[0], (0, 240, 43));
+    assert_eq!((spans[1].0, spans[1].2), (240, 43));
+    assert!(spans[1].1 > 240);
+}
+
+// ========================================================================
+// 24. DURATION LITERAL TESTS
+// ========================================================================
+
+fn durations(timeline: &tenutoc::ir::Timeline, staff: &str) -> Vec<u64> {
+    timeline.tracks[staff].events.iter().map(|e| e.duration_ticks).collect()
+}
+
+#[test]
+fn test_lexer_extended_durations() {
+    let lex = |src: &str| Token::lexer(src).map(|t| t.unwrap()).collect::<Vec<_>>();
+    assert_eq!(lex(":0.5 :3/8 :4..."), vec![
+        Token::DurationLit(":0.5".into()),
+        Token::DurationLit(":3/8".into()),
+        Token::DurationLit(":4...".into()),
+    ]);
+    assert_eq!(lex(":0.5.stacc"), vec![Token::DurationLit(":0.5".into()), Token::Dot, Token::Identifier("stacc".into())]);
+    assert_eq!(lex(":1 * 4"), vec![Token::DurationLit(":1".into()), Token::Star, Token::Integer(4)]);
+}
+
+#[test]
+fn test_breve_fraction_and_dots() {
+    let src = r#"tenuto {
+        meta { time: 8/1 }
+        def vln "Violin"
+        measure 1 { vln: c4:0.5 d:3/8 e:4. f:4... g:4.... a:2 | }
+    }"#;
+    let timeline = ir::compile(parse_str(src).unwrap()).unwrap();
+    assert_eq!(durations(&timeline, "vln"), vec![15360, 2880, 2880, 3600, 3720, 3840]);
+}
+
+#[test]
+fn test_zero_duration_is_rejected() {
+    let src = |d: &str| format!(r#"tenuto {{ def vln "Violin" measure 1 {{ vln: c4:4 d{} e f | }} }}"#, d);
+    for d in [":0", ":0/8", ":3/0", ":0.0", ":4 * 0"] {
+        let errors = ir::compile(parse_str(&src(d)).unwrap()).unwrap_err();
+        // The sticky quarter stands in, so the bar does not also overflow
+        assert_eq!(errors.iter().map(|e| e.code).collect::<Vec<_>>(), vec!["E4002"], "{}", d);
+    }
+}
+
+#[test]
+fn test_overflowing_duration_is_rejected_at_its_span() {
+    let src = |d: &str| format!(r#"tenuto {{ def vln "Violin" measure 1 {{ vln: c4:4 d{} e f | }} }}"#, d);
+    let dots = format!(":4{}", ".".repeat(70));
+    for d in [":1*4000000000000000", ":9223372036854775808.", dots.as_str()] {
+        let text = src(d);
+        let errors = ir::compile(parse_str(&text).unwrap()).unwrap_err();
+        assert_eq!(errors.iter().map(|e| e.code).collect::<Vec<_>>(), vec!["E4002"], "{}", d);
+        assert_eq!(&text[errors[0].span.range()], d, "{}", d);
+    }
+}
+
+#[test]
+fn test_multiplier_is_not_sticky() {
+    let src = r#"tenuto {
+        def vln "Violin"
+        measure 1 { vln: c4:8 * 3 d e:2 | }
+    }"#;
+    let timeline = ir::compile(parse_str(src).unwrap()).unwrap();
+    assert_eq!(durations(&timeline, "vln"), vec![2880, 960, 3840]);
+}
+
+#[test]
+fn test_multi_measure_rest_spans_bars() {
+    let src = r#"tenuto {
+        def vln "Violin"
+        def vlc "Cello"
+        measure 1 { vln: r:1 * 4 | vlc: c3:1 * 4 | }
+        measure 5 { vln: c4:1 | vlc: c3:1 | }
+    }"#;
+    let timeline = ir::compile(parse_str(src).unwrap()).unwrap();
+    assert_eq!(onsets(&timeline, "vln"), vec![30720]);
+    let starts: Vec<u64> = timeline.measures.values().map(|m| m.start_tick).collect();
+    assert_eq!(starts, vec![0, 7680, 15360, 23040, 30720]);
+
+    // Writing out a covered bar is still an overflow
+    let clash = r#"tenuto {
+        def vln "Violin"
+        measure 1 { vln: r:1 * 4 | }
+        measure { vln: c4:1 | }
+    }"#;
+    let errors = ir::compile(parse_str(clash).unwrap()).unwrap_err();
+    assert_eq!(errors[0].code, "E3001");
+    assert_eq!(errors[0].help.as_deref(), Some("A multi-bar block needs the next measure to be numbered 5 or later"));
+}
+
+#[test]
+fn test_tuplet_ratio_after_duration_lexing() {
+    let src = r#"tenuto {
+        def vln "Violin"
+        measure 1 { vln: (c4:8 d e):3/2 f:4 g:2 | }
+    }"#;
+    let timeline = ir::compile(parse_str(src).unwrap()).unwrap();
+    assert_eq!(onsets(&timeline, "vln"), vec![0, 640, 1280, 1920, 3840]);
+}
+
+// ============================================================================
+// 25. FORM & REPEAT TESTS
+// ============================================================================
+
+#[test]
+fn test_bar_lines_recorded_as_structure() {
+    let src = r#"tenuto {
+        def vln "Violin"
+        def vlc "Cello"
+        measure 1 { vln: |: c4:1 :| vlc: |: c3:1 :| }
+        measure 2 { vln: d4:1 || vlc: d3:1 }
+        measure 3 { vln: e4:1 |] vlc: e3:1 |] }
+    }"#;
+    let timeline = ir::compile(parse_str(src).unwrap()).unwrap();
+    let bars: Vec<(bool, BarLine)> = timeline.measures.values().map(|m| (m.repeat_start, m.barline)).collect();
+    assert_eq!(bars, vec![(true, BarLine::RepeatEnd), (false, BarLine::Double), (false, BarLine::Final)]);
+}
+
+#[test]
+fn test_repeat_with_voltas_unrolls() {
+    let src = r#"tenuto {
+        def vln "Violin"
+        measure 1 { vln: |: c4:1 | }
+        measure 2 { meta { volta: "1." } vln: d4:1 :| }
+        measure 3 { meta { volta: "2." } vln: e4:1 | }
+        measure 4 { vln: f4:1 |] }
+    }"#;
+    let timeline = ir::compile(parse_str(src).unwrap()).unwrap();
+    assert_eq!(timeline.measures[&2].volta, vec![1]);
+    assert_eq!(playback::order(&timeline), vec![1, 2, 1, 3, 4]);
+
+    // The written timeline is untouched; the playback one is laid end to end
+    let unrolled = playback::unroll(&timeline);
+    assert_eq!(onsets(&timeline, "vln"), vec![0, 7680, 15360, 23040]);
+    assert_eq!(onsets(&unrolled, "vln"), vec![0, 7680, 15360, 23040, 30720]);
+    assert_eq!(pitches(&unrolled, "vln"), vec![60, 62, 60, 64, 65]);
+    assert_eq!(unrolled.measures.len(), 5);
+}
+
+#[test]
+fn test_segno_coda_and_fine_jumps() {
+    let al_coda = r#"tenuto {
+        def vln "Violin"
+        measure 1 { vln: c4:1 | }
+        measure 2 { vln: d4:1.segno | }
+        measure 3 { vln: e4:1.to_coda | }
+        measure 4 { vln: f4:1.ds_al_coda || }
+        measure 5 { vln: g4:1.coda |] }
+    }"#;
+    let timeline = ir::compile(parse_str(al_coda).unwrap()).unwrap();
+    assert_eq!(timeline.measures[&2].markers, vec![Marker::Segno]);
+    assert_eq!(playback::order(&timeline), vec![1, 2, 3, 4, 2, 3, 5]);
+
+    // Repeats are not taken again after a D.C.
+    let al_fine = r#"tenuto {
+        def vln "Violin"
+        measure 1 { vln: |: c4:1 :| }
+        measure 2 { vln: d4:1.fine || }
+        measure 3 { vln: e4:1.dc_al_fine | }
+    }"#;
+    let timeline = ir::compile(parse_str(al_fine).unwrap()).unwrap();
+    assert_eq!(playback::order(&timeline), vec![1, 1, 2, 3, 1, 2]);
+}
+
+#[test]
+fn test_unrolled_tempo_follows_jumps() {
+    let src = r#"tenuto {
+        meta { tempo: 120 }
+        def vln "Violin"
+        measure 1 { vln: |: c4:1 | }
+        measure 2 { meta { tempo: 60 } vln: d4:1 :| }
+        measure 3 { vln: e4:1 | }
+    }"#;
+    let timeline = ir::compile(parse_str(src).unwrap()).unwrap();
+    let unrolled = playback::unroll(&timeline);
+    // Bar 1 comes back at 120 after bar 2 slowed down
+    assert_eq!(unrolled.tempo_map.bpm_at(7680), 60.0);
+    assert_eq!(unrolled.tempo_map.bpm_at(15360), 120.0);
+    assert_eq!(unrolled.tempo_map.bpm_at(23040), 60.0);
+    assert_eq!(unrolled.tempo_map.bpm_at(30720), 60.0);
+}
+
+#[test]
+fn test_structure_mismatch_between_staves() {
+    let src = r#"tenuto {
+        def vln "Violin"
+        def vlc "Cello"
+        measure 1 { vln: c4:1 :| vlc: c3:1 | }
+    }"#;
+    let errors = ir::compile(parse_str(src).unwrap()).unwrap_err();
+    assert_eq!(errors[0].code, "E3004");
+    assert!(errors[0].message.contains("`vlc` ends with `|`"));
+
+    // Spec 24.4: `vln` has `|:` while `vlc` has `|`
+    let repeat = r#"tenuto {
+        def vln "Violin"
+        def vlc "Cello"
+        measure 1 { vln: |: c4:1 | vlc: c3:1 | }
+    }"#;
+    let errors = ir::compile(parse_str(repeat).unwrap()).unwrap_err();
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].code, "E3004");
+    assert!(errors[0].message.contains("`vln` opens a repeat with `|:` but `vlc` does not"));
+
+    // A staff that writes no bar line follows the others
+    let lenient = r#"tenuto {
+        def vln "Violin"
+        def vlc "Cello"
+        measure 1 { vln: |: c4:1 :| vlc: c3:1 }
+    }"#;
+    assert!(ir::compile(parse_str(lenient).unwrap()).is_ok());
+}
+
+#[test]
+fn test_keyswitch_stays_with_its_bar_when_unrolled() {
+    let src = r#"tenuto {
+        def vln "Violin" keyswitch={ arco: 24, pizz: 25 }
+        measure 1 { vln: |: c4:1.arco | }
+        measure 2 { meta { volta: "1." } vln: d4:1 :| }
+        measure 3 { meta { volta: "2." } vln: e4:1.pizz | }
+    }"#;
+    let timeline = ir::compile(parse_str(src).unwrap()).unwrap();
+    let unrolled = playback::unroll(&timeline);
+    assert_eq!(playback::order(&timeline), vec![1, 2, 1, 3]);
+    // Each keyswitch fires just before the note it selects, in playback order
+    assert_eq!(keyswitches(&unrolled, "vln"), vec![(0, 24), (15330, 24), (23010, 25)]);
+    let ons = note_ons(&midi::export(&unrolled).unwrap(), 1);
+    assert_eq!(ons, vec![(0, 24), (0, 60), (7680, 62), (15330, 24), (15360, 60), (23010, 25), (23040, 64)]);
+}
+
+// ============================================================================
+// 26. FERMATA TESTS
+// ============================================================================
+
+#[test]
+fn test_fermata_records_global_hold() {
+    let src = r#"tenuto {
+        def vln "Violin"
+        def vlc "Cello"
+        measure 1 { vln: c4:2 d:2.fermata | vlc: c3:1.fermata | }
+        measure 2 { vln: e4:1 | vlc: e3:1 | }
+    }"#;
+    let timeline = ir::compile(parse_str(src).unwrap()).unwrap();
+    // The longest held note sets the pause
+    assert_eq!(timeline.holds.iter().map(|(&t, &h)| (t, h)).collect::<Vec<_>>(), vec![(7680, 7680)]);
+    // Notated positions stay on the grid
+    assert_eq!(onsets(&timeline, "vln"), vec![0, 3840, 7680]);
+    assert_eq!(timeline.measures[&2].start_tick, 7680);
+}
+
+#[test]
+fn test_fermata_shifts_every_track_in_playback() {
+    let src = r#"tenuto {
+        def vln "Violin"
+        def vlc "Cello"
+        measure 1 { vln: c4:2 d:2.fermata | vlc: c3:1 | }
+        measure 2 { vln: e4:1 | vlc: e3:1 | }
+    }"#;
+    let timeline = ir::compile(parse_str(src).unwrap()).unwrap();
+    let unrolled = playback::unroll(&timeline);
+    assert_eq!(onsets(&unrolled, "vln"), vec![0, 3840, 11520]);
+    assert_eq!(onsets(&unrolled, "vlc"), vec![0, 11520]);
+    // Both held notes sustain through the pause
+    let sounding: Vec<u64> = unrolled.tracks["vlc"].events.iter().map(|e| e.sounding_ticks).collect();
+    assert_eq!(sounding[0], 6912 + 3840);
+    assert_eq!(unrolled.tracks["vln"].events[1].sounding_ticks, 3456 + 3840);
+    assert_eq!(unrolled.measures[&1].length_ticks, 11520);
+    assert_eq!(unrolled.measures[&2].start_tick, 11520);
+}
+
+#[test]
+fn test_fermata_factor_overrides() {
+    let src = r#"tenuto {
+        meta { fermata: 1.5 }
+        def vln "Violin"
+        measure 1 { vln: c4:1.fermata | }
+        measure 2 { meta { fermata: 3 } vln: d4:1.fermata | }
+    }"#;
+    let timeline = ir::compile(parse_str(src).unwrap()).unwrap();
+    assert_eq!(timeline.holds.get(&7680), Some(&3840));
+    assert_eq!(timeline.holds.get(&15360), Some(&15360));
+
+    let invalid = r#"tenuto {
+        def vln "Violin"
+        measure 1 { meta { fermata: 0.5 } vln: c4:1.fermata | }
+    }"#;
+    let errors = ir::compile(parse_str(invalid).unwrap()).unwrap_err();
+    assert_eq!(errors[0].code, "E4002");
+}
+
+// ============================================================================
+// 27. SWING & HUMANIZE TESTS
+// ============================================================================
+
+/// Onsets as performed: the grid tick plus its playback displacement.
+fn performed(timeline: &tenutoc::ir::Timeline, staff: &str) -> Vec<i64> {
+    timeline.tracks[staff].events.iter().map(|e| e.tick as i64 + e.offset_ticks).collect()
+}
+
+#[test]
+fn test_swing_delays_off_beat_eighths() {
+    let src = r#"tenuto {
+        meta { tempo: 120, swing: 66 }
+        def vln "Violin"
+        measure 1 { vln: c4:8 d e f g:2 | }
+    }"#;
+    let timeline = ir::compile(parse_str(src).unwrap()).unwrap();
+    let swung = playback::render(&timeline);
+    assert_eq!(performed(&swung, "vln"), vec![0, 1267, 1920, 3187, 3840]);
+    // The long-short pair fills the beat
+    assert_eq!(swung.tracks["vln"].events[0].sounding_ticks, 1140);
+    // The notated timeline is untouched
+    assert_eq!(performed(&timeline, "vln"), vec![0, 960, 1920, 2880, 3840]);
+}
+
+#[test]
+fn test_swing_grid_detection_and_override() {
+    let slow = r#"tenuto {
+        meta { tempo: 50, swing: 66 }
+        def vln "Violin"
+        measure 1 { vln: c4:16 d e f g:4. a:4 | }
+    }"#;
+    let timeline = ir::compile(parse_str(slow).unwrap()).unwrap();
+    assert_eq!(performed(&playback::render(&timeline), "vln")[..4], [0, 634, 960, 1594]);
+
+    let explicit = r#"tenuto {
+        meta { tempo: 120, swing: 75, swing_grid: ":16" }
+        def vln "Violin"
+        measure 1 { vln: c4:16 d e f g:4. a:4 | }
+    }"#;
+    let timeline = ir::compile(parse_str(explicit).unwrap()).unwrap();
+    assert_eq!(timeline.feel.swing_grid, Some(480));
+    assert_eq!(performed(&playback::render(&timeline), "vln")[..4], [0, 720, 960, 1680]);
+}
+
+#[test]
+fn test_humanize_is_seeded_and_bounded() {
+    let src = |seed: u64| format!(r#"tenuto {{
+        meta {{ humanize: 0.05, seed: {} }}
+        def vln "Violin"
+        def vlc "Cello"
+        measure 1 {{ vln: c4:8 d e f g a b c5 | vlc: c3:4 d e f | }}
+    }}"#, seed);
+    let compile = |seed| ir::compile(parse_str(&src(seed)).unwrap()).unwrap();
+
+    let timeline = compile(7);
+    let first = playback::render(&timeline);
+    let again = playback::render(&compile(7));
+    assert_eq!(performed(&first, "vln"), performed(&again, "vln"));
+    assert_eq!(velocities(&first, "vlc"), velocities(&again, "vlc"));
+    assert_eq!(midi::export(&first).unwrap(), midi::export(&again).unwrap());
+    assert_ne!(performed(&first, "vln"), performed(&playback::render(&compile(8)), "vln"));
+
+    // Within 5% of a 16th note and of the velocity, and never on the written copy
+    for (i, onset) in performed(&first, "vln").into_iter().enumerate() {
+        assert!((onset - i as i64 * 960).abs() <= 24);
+    }
+    assert!(velocities(&first, "vln").iter().all(|&v| (v as i64 - 100).abs() <= 5));
+    assert!(velocities(&timeline, "vln").iter().all(|&v| v == 100));
+}
+
+#[test]
+fn test_humanize_keeps_keyswitch_ahead_of_its_note() {
+    let src = r#"tenuto {
+        meta { humanize: 1, seed: 3 }
+        def vln "Violin" keyswitch={ arco: 24, pizz: 25 }
+        measure 1 { vln: c4:4.pizz d.arco e.pizz f.arco | }
+    }"#;
+    let performed = playback::render(&ir::compile(parse_str(src).unwrap()).unwrap());
+    let events = &performed.tracks["vln"].events;
+    assert_eq!(keyswitches(&performed, "vln").len(), 4);
+    for (i, switch) in events.iter().enumerate().filter(|(_, e)| matches!(e.kind, ir::EventKind::KeySwitch { .. })) {
+        let note = &events[i + 1];
+        let lead = (note.tick as i64 + note.offset_ticks) - (switch.tick as i64 + switch.offset_ticks);
+        assert_eq!(lead, switch.sounding_ticks as i64);
+    }
+}
+
+#[test]
+fn test_feel_meta_validation() {
+    let src = r#"tenuto {
+        meta { swing: 120, humanize: 2 }
+        def vln "Violin"
+        measure 1 { vln: c4:1 | }
+    }"#;
+    let timeline = ir::compile(parse_str(src).unwrap()).unwrap();
+    assert_eq!(timeline.feel.swing, Some(100.0));
+    assert_eq!(timeline.feel.humanize, 1.0);
+    assert_eq!(timeline.warnings.iter().filter(|w| w.code == "W4003").count(), 2);
+    // Each warning points at the offending value
+    let spans: Vec<&str> = timeline.warnings.iter().map(|w| &src[w.span.range()]).collect();
+    assert_eq!(spans, vec!["120", "2"]);
+
+    let invalid = r#"tenuto {
+        meta { swing_grid: 8 }
+        def vln "Violin"
+        measure 1 { vln: c4:1 | }
+    }"#;
+    let errors = ir::compile(parse_str(invalid).unwrap()).unwrap_err();
+    assert_eq!(errors[0].code, "E4002");
+}
+
+// ============================================================================
+// 28. MIXER TESTS
+// ============================================================================
+
+fn controllers(bytes: &[u8], track: usize) -> Vec<(u32, u8, u8)> {
+    let smf = midly::Smf::parse(bytes).unwrap();
+    let mut tick = 0;
+    let mut changes = Vec::new();
+    for event in &smf.tracks[track] {
+        tick += event.delta.as_int();
+        if let midly::TrackEventKind::Midi { message: midly::MidiMessage::Controller { controller, value }, .. } = event.kind {
+            changes.push((tick, controller.as_int(), value.as_int()));
+        }
+    }
+    changes
+}
+
+#[test]
+fn test_parser_staff_meta_keys() {
+    let src = r#"tenuto { meta { vln.pan: -0.5, a.vol: 1, tempo: 120 } }"#;
+    let score = parse_str(src).unwrap();
+    let TopLevel::Meta(kvs, _) = &score.items[0] else { panic!("expected meta") };
+    assert_eq!((kvs[0].key.as_str(), &kvs[0].value), ("vln.pan", &Value::Float(-0.5)));
+    assert_eq!((kvs[1].key.as_str(), &kvs[1].value), ("a.vol", &Value::Num(1)));
+    assert_eq!(&src[kvs[0].span.range()], "-0.5");
+}
+
+#[test]
+fn test_mixer_defaults_become_controllers() {
+    let src = r#"tenuto {
+        def vln "Violin" vol=0.5 pan=-1.0 reverb=0.8 chorus=0
+        def vlc "Cello"
+        measure 1 { vln: c4:1 | vlc: c3:1 | }
+    }"#;
+    let timeline = ir::compile(parse_str(src).unwrap()).unwrap();
+    let bytes = midi::export(&timeline).unwrap();
+    let mut ccs = controllers(&bytes, 2);
+    ccs.sort();
+    assert_eq!(ccs, vec![(0, 7, 64), (0, 10, 0), (0, 91, 102), (0, 93, 0)]);
+    // Untouched staves keep the synth's own defaults
+    assert!(controllers(&bytes, 1).is_empty());
+}
+
+#[test]
+fn test_mixer_ramp_across_measure() {
+    let src = r#"tenuto {
+        meta { vln.pan: 0.5 }
+        def vln "Violin"
+        measure 1 { meta { vln.vol: [1.0, 0.0] } vln: c4:1 | }
+        measure 2 { meta { vln.pan: -0.5, vln.reverb: 0.25 } vln: d4:1 | }
+    }"#;
+    let timeline = ir::compile(parse_str(src).unwrap()).unwrap();
+    let ccs = controllers(&midi::export(&timeline).unwrap(), 1);
+    let vol: Vec<_> = ccs.iter().filter(|c| c.1 == 7).collect();
+    // Sampled about every 10 ms, one message per distinct value
+    assert_eq!(vol.len(), 128);
+    assert_eq!(vol[0], &(0, 7, 127));
+    // The last step rounds to the target within one sample of the bar line
+    assert!(vol[127].2 == 0 && (7642..=7680).contains(&vol[127].0));
+    assert!(vol.windows(2).all(|w| w[0].0 < w[1].0 && w[0].2 > w[1].2));
+    let pan: Vec<_> = ccs.iter().filter(|c| c.1 == 10).collect();
+    assert_eq!(pan, vec![&(0, 10, 95), &(7680, 10, 32)]);
+    assert!(ccs.contains(&(7680, 91, 32)));
+}
+
+#[test]
+fn test_mute_and_solo_choose_audible_tracks() {
+    let src = r#"tenuto {
+        meta { vla.mute: true }
+        def vln "Violin" solo=true
+        def vla "Viola" solo=true
+        def vlc "Cello"
+        measure 1 { vln: c4:1 | vla: g3:1 | vlc: c3:1 | }
+    }"#;
+    let timeline = ir::compile(parse_str(src).unwrap()).unwrap();
+    // Muted staves keep their logical state
+    assert_eq!(timeline.tracks["vla"].events.len(), 1);
+    let bytes = midi::export(&timeline).unwrap();
+    // Tracks are sorted: vla, vlc, vln
+    assert!(note_ons(&bytes, 1).is_empty());
+    assert!(note_ons(&bytes, 2).is_empty());
+    assert_eq!(note_ons(&bytes, 3), vec![(0, 60)]);
+}
+
+#[test]
+fn test_mixer_automation_follows_repeats() {
+    let src = r#"tenuto {
+        def vln "Violin"
+        measure 1 { meta { vln.vol: 0.5 } vln: |: c4:1 | }
+        measure 2 { meta { vln.vol: 1.0 } vln: d4:1 :| }
+    }"#;
+    let timeline = ir::compile(parse_str(src).unwrap()).unwrap();
+    let ccs = controllers(&midi::export(&playback::unroll(&timeline)).unwrap(), 1);
+    assert_eq!(ccs, vec![(0, 7, 64), (7680, 7, 127), (15360, 7, 64), (23040, 7, 127)]);
+}
+
+#[test]
+fn test_mixer_meta_errors() {
+    let src = r#"tenuto {
+        def vln "Violin" vol=1.5
+        measure 1 { meta { vlx.vol: 0.5, vln.gain: 1, vln.mute: true } vln: c4:1 | }
+    }"#;
+    let errors = ir::compile(parse_str(src).unwrap()).unwrap_err();
+    let codes: Vec<&str> = errors.iter().map(|d| d.code).collect();
+    assert!(codes.contains(&"W4003"));
+    let range = errors.iter().find(|d| d.code == "W4003").unwrap().span.range();
+    assert_eq!(&src[range], "1.5");
+    assert!(codes.contains(&"E2001"));
+    // `vln.gain` is not a mixer parameter; `vln.mute` cannot change per measure
+    assert_eq!(codes.iter().filter(|&&c| c == "E4002").count(), 1);
+    let mute = errors.iter().find(|d| d.message.contains("`mute`")).unwrap();
+    assert_eq!((mute.code, &src[mute.span.range()]), ("W4003", "true"));
+}
+
+// ============================================================================
+// 29. CONTROL CHANGE TESTS
+// ============================================================================
+
+#[test]
+fn test_discrete_cc_precedes_note_on() {
+    let src = r#"tenuto {
+        def vln "Violin"
+        measure 1 { vln: c4:2.cc(64, 127) d:2.cc(64, 0).cc(1, 40) | }
+    }"#;
+    let timeline = ir::compile(parse_str(src).unwrap()).unwrap();
+    let controls = &timeline.tracks["vln"].events[1].controls;
+    assert_eq!(controls.iter().map(|c| (c.controller, c.to)).collect::<Vec<_>>(), vec![(64, 0.0), (1, 40.0)]);
+
+    let bytes = midi::export(&timeline).unwrap();
+    assert_eq!(controllers(&bytes, 1), vec![(0, 64, 127), (3840, 64, 0), (3840, 1, 40)]);
+    // The controller change is sent before the note it belongs to
+    let smf = midly::Smf::parse(&bytes).unwrap();
+    let kinds: Vec<&str> = smf.tracks[1].iter().filter_map(|e| match e.kind {
+        midly::TrackEventKind::Midi { message: midly::MidiMessage::Controller { .. }, .. } => Some("cc"),
+        midly::TrackEventKind::Midi { message: midly::MidiMessage::NoteOn { .. }, .. } => Some("on"),
+        _ => None,
+    }).collect();
+    assert_eq!(kinds, vec!["cc", "on", "cc", "cc", "on"]);
+}
+
+#[test]
+fn test_cc_ramp_spans_host_event() {
+    let src = r#"tenuto {
+        meta { tempo: 120 }
+        def vln "Violin"
+        measure 1 { vln: c4:1.cc(11, [0, 100], "exp") | }
+    }"#;
+    let timeline = ir::compile(parse_str(src).unwrap()).unwrap();
+    let ramp = controllers(&midi::export(&timeline).unwrap(), 1);
+    assert_eq!(ramp.first(), Some(&(0, 11, 0)));
+    let (tick, _, value) = *ramp.last().unwrap();
+    assert!(value == 100 && (7642..=7680).contains(&tick));
+    // About every 10 ms (38 ticks at 120 BPM), slow at first for an exp curve
+    assert!(ramp.windows(2).all(|w| w[1].0 - w[0].0 >= 38 && w[1].2 > w[0].2));
+    let halfway = ramp.iter().rev().find(|c| c.0 <= 3840).unwrap();
+    assert!(halfway.2 < 20);
+}
+
+#[test]
+fn test_cc_follows_chords_and_repeats() {
+    let src = r#"tenuto {
+        def vln "Violin"
+        measure 1 { vln: |: [c4 e4 g4]:1.cc(1, 90) :| }
+    }"#;
+    let timeline = ir::compile(parse_str(src).unwrap()).unwrap();
+    // Carried once per chord
+    let carriers = timeline.tracks["vln"].events.iter().filter(|e| !e.controls.is_empty()).count();
+    assert_eq!(carriers, 1);
+    let ccs = controllers(&midi::export(&playback::unroll(&timeline)).unwrap(), 1);
+    assert_eq!(ccs, vec![(0, 1, 90), (7680, 1, 90)]);
+}
+
+#[test]
+fn test_cc_argument_errors() {
+    let src = r#"tenuto {
+        def vln "Violin"
+        measure 1 { vln: c4:4.cc(11) d.cc(11, [0, 10], "wobble") e.cc(200, 64) f | }
+    }"#;
+    let errors = ir::compile(parse_str(src).unwrap()).unwrap_err();
+    let codes: Vec<&str> = errors.iter().map(|d| d.code).collect();
+    assert_eq!(codes, vec!["E4002", "E4002", "W4003"]);
+}